@@ -0,0 +1,518 @@
+use std::{io::Write, net::ToSocketAddrs, sync::Arc, time::SystemTime};
+
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized},
+    http::header,
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    configs::{NesConfig, RuntimeOverrides, NES_CONFIG_FILENAME},
+    matcher::PatternSet,
+    routing::RouteRule,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterList {
+    Whitelist,
+    Blacklist,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterChange {
+    pub list: FilterList,
+    pub pattern: String,
+}
+
+pub struct AdminState {
+    pipelines: Vec<Arc<ArcSwap<NesConfig>>>,
+    /// Serializes changes, the overrides file being read and written by each
+    updating: Mutex<()>,
+}
+
+impl AdminState {
+    pub fn new(pipelines: Vec<Arc<ArcSwap<NesConfig>>>) -> Self {
+        Self {
+            pipelines,
+            updating: Mutex::new(()),
+        }
+    }
+
+    /// Config of the `{pipeline}` of the path, which can be left out when
+    /// there is a single pipeline.
+    fn config(&self, req: &HttpRequest) -> actix_web::Result<&Arc<ArcSwap<NesConfig>>> {
+        match req.match_info().get("pipeline") {
+            Some(name) => self
+                .pipelines
+                .iter()
+                .find(|config| config.load().pipeline == name)
+                .ok_or_else(|| ErrorNotFound(format!("No pipeline `{}`", name))),
+            None if self.pipelines.len() == 1 => Ok(&self.pipelines[0]),
+            None => Err(ErrorNotFound(
+                "Several pipelines are configured, use /pipelines/<name>/config",
+            )),
+        }
+    }
+
+    fn authorize(&self, req: &HttpRequest) -> actix_web::Result<()> {
+        // Admin keys are shared by the pipelines
+        let config = self.pipelines[0].load();
+        let token = match &config.admin_token {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(value) if constant_time_eq(value.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(ErrorUnauthorized("Missing or invalid bearer token")),
+        }
+    }
+
+    /// Applies `change` to the runtime overrides, then saves them, swaps
+    /// the config in and records the change in the audit log.
+    async fn update(
+        &self,
+        req: &HttpRequest,
+        action: &str,
+        detail: serde_json::Value,
+        change: impl FnOnce(&NesConfig, &mut RuntimeOverrides) -> actix_web::Result<()>,
+    ) -> actix_web::Result<HttpResponse> {
+        self.authorize(req)?;
+
+        let config = self.config(req)?;
+        let _updating = self.updating.lock().await;
+        let current = config.load_full();
+        let mut overrides =
+            RuntimeOverrides::load(&current.runtime_path).map_err(ErrorInternalServerError)?;
+        change(&current, &mut overrides)?;
+
+        overrides
+            .save(&current.runtime_path)
+            .map_err(ErrorInternalServerError)?;
+        let mut new = NesConfig::clone(&current);
+        new.apply_overrides(overrides);
+        config.store(Arc::new(new));
+
+        info!(
+            target: crate::INDEXER,
+            "Admin API {} on pipeline `{}`: {}", action, current.pipeline, detail
+        );
+        if let Err(err) = audit(&current, req, action, &detail) {
+            tracing::warn!("Could not write admin audit log: {:?}", err);
+        }
+
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+/// Compares without stopping at the first difference, so the response
+/// time doesn't tell how much of a guessed token is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Without an `admin_token` anyone reaching `addr` can change the config,
+/// so it must then only listen on loopback.
+fn check_exposure(addr: &str, admin_token: Option<&String>) -> anyhow::Result<()> {
+    if admin_token.is_some() {
+        return Ok(());
+    }
+    if let Some(exposed) = addr
+        .to_socket_addrs()?
+        .find(|addr| !addr.ip().is_loopback())
+    {
+        anyhow::bail!(
+            "admin_addr {} listens on {}, set admin_token or listen on loopback",
+            addr,
+            exposed.ip()
+        );
+    }
+
+    Ok(())
+}
+
+fn audit(
+    nes_config: &NesConfig,
+    req: &HttpRequest,
+    action: &str,
+    detail: &serde_json::Value,
+) -> anyhow::Result<()> {
+    let entry = serde_json::json!({
+        "time": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "peer": req.peer_addr().map(|addr| addr.to_string()),
+        "pipeline": nes_config.pipeline,
+        "action": action,
+        "detail": detail,
+    });
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&nes_config.admin_audit_log)?;
+    writeln!(file, "{}", entry)?;
+
+    Ok(())
+}
+
+/// `set` with `pattern` added, or removed when `add` is false.
+/// `None` when there is nothing to change.
+pub fn change_pattern(
+    set: &PatternSet,
+    pattern: &str,
+    add: bool,
+) -> anyhow::Result<Option<PatternSet>> {
+    let sources = set.sources();
+    let contains = sources.iter().any(|source| source == pattern);
+    let sources: Vec<String> = match (add, contains) {
+        (true, false) => sources
+            .iter()
+            .cloned()
+            .chain(std::iter::once(pattern.to_string()))
+            .collect(),
+        (false, true) => sources
+            .iter()
+            .filter(|source| *source != pattern)
+            .cloned()
+            .collect(),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(PatternSet::try_from(sources)?))
+}
+
+fn filter_list(nes_config: &NesConfig, list: FilterList) -> &PatternSet {
+    match list {
+        FilterList::Whitelist => &nes_config.whitelist_contract_ids,
+        FilterList::Blacklist => &nes_config.blacklist_contract_ids,
+    }
+}
+
+async fn get_filters(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> actix_web::Result<HttpResponse> {
+    state.authorize(&req)?;
+    let config = state.config(&req)?.load();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "whitelist": config.whitelist_contract_ids,
+        "blacklist": config.blacklist_contract_ids,
+    })))
+}
+
+async fn change_filter(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    body: web::Json<FilterChange>,
+    add: bool,
+) -> actix_web::Result<HttpResponse> {
+    let detail = serde_json::to_value(&*body).map_err(ErrorInternalServerError)?;
+    let FilterChange { list, pattern } = body.into_inner();
+    let action = if add { "add_filter" } else { "remove_filter" };
+
+    state
+        .update(&req, action, detail, |current, overrides| {
+            let set = change_pattern(filter_list(current, list), &pattern, add)
+                .map_err(ErrorBadRequest)?
+                .ok_or_else(|| match add {
+                    true => ErrorBadRequest(format!("`{}` is already listed", pattern)),
+                    false => ErrorNotFound(format!("`{}` is not listed", pattern)),
+                })?;
+            match list {
+                FilterList::Whitelist => overrides.whitelist_contract_ids = Some(set),
+                FilterList::Blacklist => overrides.blacklist_contract_ids = Some(set),
+            }
+            Ok(())
+        })
+        .await
+}
+
+async fn add_filter(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    body: web::Json<FilterChange>,
+) -> actix_web::Result<HttpResponse> {
+    change_filter(req, state, body, true).await
+}
+
+async fn remove_filter(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    body: web::Json<FilterChange>,
+) -> actix_web::Result<HttpResponse> {
+    change_filter(req, state, body, false).await
+}
+
+/// Drops the runtime overrides of the pipeline, its filters and routes
+/// going back to the ones of `nes.toml`.
+async fn drop_overrides(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> actix_web::Result<HttpResponse> {
+    state.authorize(&req)?;
+
+    let config = state.config(&req)?;
+    let _updating = state.updating.lock().await;
+    let current = config.load_full();
+    if current.runtime_path.exists() {
+        std::fs::remove_file(&current.runtime_path).map_err(ErrorInternalServerError)?;
+    }
+    let file_config = NesConfig::load_pipelines(current.home_dir.clone())
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|(nes_config, _)| nes_config)
+        .find(|nes_config| nes_config.pipeline == current.pipeline)
+        .ok_or_else(|| {
+            ErrorNotFound(format!(
+                "No pipeline `{}` in {}",
+                current.pipeline, NES_CONFIG_FILENAME
+            ))
+        })?;
+
+    let mut new = NesConfig::clone(&current);
+    new.whitelist_contract_ids = file_config.whitelist_contract_ids;
+    new.blacklist_contract_ids = file_config.blacklist_contract_ids;
+    new.routes = file_config.routes;
+    new.file_routes = file_config.file_routes;
+    config.store(Arc::new(new));
+
+    info!(
+        target: crate::INDEXER,
+        "Admin API dropped the overrides of pipeline `{}`", current.pipeline
+    );
+    if let Err(err) = audit(&current, &req, "drop_overrides", &serde_json::json!({})) {
+        tracing::warn!("Could not write admin audit log: {:?}", err);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lists the routes, each with the `id` that removes it.
+async fn get_routes(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> actix_web::Result<HttpResponse> {
+    state.authorize(&req)?;
+
+    let routes = state
+        .config(&req)?
+        .load()
+        .routes
+        .iter()
+        .map(|route| {
+            let mut json = serde_json::to_value(route)?;
+            json["id"] = route.id().into();
+            Ok(json)
+        })
+        .collect::<serde_json::Result<Vec<_>>>()
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(routes))
+}
+
+async fn add_route(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    body: web::Json<RouteRule>,
+) -> actix_web::Result<HttpResponse> {
+    let route = body.into_inner();
+    let id = route.id();
+    let detail = serde_json::to_value(&route).map_err(ErrorInternalServerError)?;
+
+    state
+        .update(&req, "add_route", detail, |current, overrides| {
+            if let Some(index) = overrides
+                .removed_routes
+                .iter()
+                .position(|removed| *removed == id)
+            {
+                overrides.removed_routes.remove(index);
+            } else if current.routes.iter().any(|route| route.id() == id) {
+                return Err(ErrorBadRequest(format!("Route `{}` already exists", id)));
+            } else {
+                overrides.added_routes.push(route);
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Removes the route with the `id` listed by `GET /config/routes`, an
+/// added one from the overrides, a `nes.toml` one by recording its id.
+async fn remove_route(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> actix_web::Result<HttpResponse> {
+    let id = req.match_info().query("id").to_string();
+
+    state
+        .update(
+            &req,
+            "remove_route",
+            serde_json::json!({ "id": id }),
+            |current, overrides| {
+                let added = overrides.added_routes.len();
+                overrides.added_routes.retain(|route| route.id() != id);
+                if overrides.added_routes.len() < added {
+                    return Ok(());
+                }
+                let in_file = current.file_routes.iter().any(|route| route.id() == id);
+                if !in_file || overrides.removed_routes.contains(&id) {
+                    return Err(ErrorNotFound(format!("No route `{}`", id)));
+                }
+                overrides.removed_routes.push(id);
+                Ok(())
+            },
+        )
+        .await
+}
+
+fn config_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/config/filters")
+            .route(web::get().to(get_filters))
+            .route(web::post().to(add_filter))
+            .route(web::delete().to(remove_filter)),
+    )
+    .service(
+        web::resource("/config/routes")
+            .route(web::get().to(get_routes))
+            .route(web::post().to(add_route)),
+    )
+    .service(web::resource("/config/routes/{id}").route(web::delete().to(remove_route)))
+    .service(web::resource("/config/overrides").route(web::delete().to(drop_overrides)));
+}
+
+/// Serves the admin API on `admin_addr`. Filters and routes changed here
+/// are swapped into the config of their pipeline, picked up from the next
+/// block on. `/pipelines/<name>/config/...` targets a pipeline, the
+/// shorter `/config/...` works when there is a single one.
+pub async fn serve_admin(pipelines: Vec<Arc<ArcSwap<NesConfig>>>) -> anyhow::Result<()> {
+    let addr = match pipelines[0].load().admin_addr.clone() {
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+    check_exposure(&addr, pipelines[0].load().admin_token.as_ref())?;
+    let state = web::Data::new(AdminState::new(pipelines));
+
+    info!(target: crate::INDEXER, "Admin API listening on {}", addr);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .configure(config_routes)
+            .service(web::scope("/pipelines/{pipeline}").configure(config_routes))
+    })
+    .workers(1)
+    .bind(addr)?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn changes_patterns() {
+        let set = PatternSet::try_from(vec!["a.near".to_string()]).unwrap();
+
+        let added = change_pattern(&set, "*.paras.near", true).unwrap().unwrap();
+        assert!(added.is_match("x.paras.near"));
+        assert_eq!(added.sources(), ["a.near", "*.paras.near"]);
+        assert!(change_pattern(&added, "a.near", true).unwrap().is_none());
+        assert!(change_pattern(&added, "^(", true).is_err());
+
+        let removed = change_pattern(&added, "a.near", false).unwrap().unwrap();
+        assert!(!removed.is_match("a.near"));
+        assert!(change_pattern(&removed, "a.near", false).unwrap().is_none());
+
+        let overrides = RuntimeOverrides {
+            whitelist_contract_ids: Some(removed),
+            ..Default::default()
+        };
+        let dir = temp_dir("runtime");
+        let path = dir.join("nes.runtime.json");
+        overrides.save(&path).unwrap();
+        let loaded = RuntimeOverrides::load(&path).unwrap();
+        assert_eq!(loaded.keys(), ["filters.whitelist_contract_ids"]);
+        assert_eq!(
+            loaded.whitelist_contract_ids.unwrap().sources(),
+            ["*.paras.near"]
+        );
+        assert!(loaded.added_routes.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merges_route_overrides() {
+        let home_dir = temp_dir("route-overrides");
+        let path = home_dir.join(NES_CONFIG_FILENAME);
+        let nes_toml = |routes: &str| {
+            format!(
+                "{}\n[kafka]\n\"bootstrap.servers\"=\"localhost:9092\"\n",
+                routes
+            )
+        };
+        let route = |standard: &str| {
+            format!(
+                "[[sinks.routes]]\nstandard=\"{}\"\ntopics=[\"{}\"]\n",
+                standard, standard
+            )
+        };
+        let standards = |nes_config: &NesConfig| -> Vec<String> {
+            nes_config
+                .routes
+                .iter()
+                .map(|route| route.standard.as_ref().unwrap().to_string())
+                .collect()
+        };
+
+        std::fs::write(&path, nes_toml(&(route("nep171") + &route("nep141")))).unwrap();
+        let current = NesConfig::pipelines(home_dir.clone()).unwrap().remove(0);
+        let added: RouteRule =
+            serde_json::from_value(serde_json::json!({ "standard": "nep245" })).unwrap();
+        let overrides = RuntimeOverrides {
+            added_routes: vec![added],
+            removed_routes: vec![current.routes[1].id()],
+            ..Default::default()
+        };
+        overrides.save(&current.runtime_path).unwrap();
+        assert_eq!(overrides.keys(), ["sinks.routes"]);
+
+        // Routes edited in nes.toml still apply, the overrides on top
+        std::fs::write(
+            &path,
+            nes_toml(&(route("nep171") + &route("nep141") + &route("nep297"))),
+        )
+        .unwrap();
+        let reloaded = NesConfig::pipelines(home_dir.clone()).unwrap().remove(0);
+        assert_eq!(standards(&reloaded), ["nep171", "nep297", "nep245"]);
+        assert_eq!(reloaded.file_routes.len(), 3);
+        assert_eq!(reloaded.routes[0].id(), current.routes[0].id());
+
+        std::fs::remove_dir_all(home_dir).unwrap();
+    }
+
+    #[test]
+    fn guards_access() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+
+        let token = "secret".to_string();
+        assert!(check_exposure("127.0.0.1:3030", None).is_ok());
+        assert!(check_exposure("0.0.0.0:3030", None).is_err());
+        assert!(check_exposure("0.0.0.0:3030", Some(&token)).is_ok());
+    }
+}
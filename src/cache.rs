@@ -0,0 +1,178 @@
+use std::{
+    hash::Hash,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    expires_at: SystemTime,
+}
+
+/// In-process LRU cache whose entries expire after `ttl`.
+pub struct TtlCache<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, (V, SystemTime)>>,
+    ttl: Duration,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TtlCache<K, V> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.lock().unwrap().cap()
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > SystemTime::now() => Some(value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let expires_at = SystemTime::now() + self.ttl;
+        self.entries.lock().unwrap().put(key, (value, expires_at));
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.entries.lock().unwrap().pop(key);
+    }
+
+    /// Removes every entry whose key matches `predicate`.
+    pub fn remove_where(&self, predicate: impl Fn(&K) -> bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<K> = entries
+            .iter()
+            .filter(|(key, _)| predicate(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter().for_each(|key| {
+            entries.pop(key);
+        });
+    }
+}
+
+impl<K, V> TtlCache<K, V>
+where
+    K: Hash + Eq + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    /// Loads entries saved by [`TtlCache::save`], skipping the expired ones.
+    pub fn load(&self, path: &Path) -> anyhow::Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let file = std::fs::File::open(path)?;
+        let saved: Vec<Entry<K, V>> = serde_json::from_reader(std::io::BufReader::new(file))?;
+
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        // Saved from the most to the least recently used
+        saved
+            .into_iter()
+            .rev()
+            .filter(|entry| entry.expires_at > now)
+            .for_each(|entry| {
+                entries.put(entry.key, (entry.value, entry.expires_at));
+            });
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let saved: Vec<Entry<K, V>> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, (value, expires_at))| Entry {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: *expires_at,
+            })
+            .collect();
+
+        let tmp_path = path.with_extension("tmp");
+        let file = std::fs::File::create(&tmp_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &saved)?;
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+/// Saves the cache to `path` every `interval`.
+pub async fn persist_periodically<K, V>(
+    cache: Arc<TtlCache<K, V>>,
+    path: PathBuf,
+    interval: Duration,
+) where
+    K: Hash + Eq + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(err) = cache.save(&path) {
+            warn!("Could not save cache to {:?}: {:?}", path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn expires_and_evicts() {
+        let cache: TtlCache<String, u64> = TtlCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        cache.insert("c".to_string(), 3);
+        assert_eq!(cache.get(&"a".to_string()), None);
+        assert_eq!(cache.get(&"c".to_string()), Some(3));
+
+        cache.remove_where(|key| key == "c");
+        assert_eq!(cache.get(&"c".to_string()), None);
+
+        let cache: TtlCache<String, u64> = TtlCache::new(2, Duration::from_secs(0));
+        cache.insert("a".to_string(), 1);
+        assert_eq!(cache.get(&"a".to_string()), None);
+    }
+
+    #[test]
+    fn persists() {
+        let dir = temp_dir("ttl-cache");
+        let path = dir.join("cache.json");
+        let cache: TtlCache<(String, String), u64> = TtlCache::new(10, Duration::from_secs(60));
+        cache.insert(("nft.near".to_string(), "1".to_string()), 1);
+        cache.save(&path).unwrap();
+
+        let loaded: TtlCache<(String, String), u64> = TtlCache::new(10, Duration::from_secs(60));
+        loaded.load(&path).unwrap();
+        assert_eq!(
+            loaded.get(&("nft.near".to_string(), "1".to_string())),
+            Some(1)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use itertools::Itertools;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+
+use crate::{
+    configs::{NesConfig, NES_CONFIG_FILENAME},
+    event_types::NearEvent,
+    matcher::{Pattern, PatternSet},
+};
+
+/// Events the topic names are checked with
+const SAMPLE_EVENTS: [&str; 2] = [
+    r#"{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"alice.near","token_ids":["1"]}],"emit_info":{"receipt_id":"receipt","block_timestamp":0,"block_height":0,"shard_id":0,"contract_account_id":"x.paras.near","predecessor_id":"alice.near"}}"#,
+    r#"{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"bob.near","amount":"1"}],"emit_info":{"receipt_id":"receipt","block_timestamp":0,"block_height":0,"shard_id":0,"contract_account_id":"token.near","predecessor_id":"alice.near"}}"#,
+];
+
+const SASL_CREDENTIAL_MECHANISMS: [&str; 3] = ["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-512"];
+
+/// Kafka accepts up to 249 characters among `[a-zA-Z0-9._-]`.
+fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= 249
+        && topic != "."
+        && topic != ".."
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Whitelisted accounts the blacklist rejects anyway.
+fn contradictions(name: &str, whitelist: &PatternSet, blacklist: &PatternSet) -> Vec<String> {
+    whitelist
+        .sources()
+        .iter()
+        .filter(|source| matches!(source.parse(), Ok(Pattern::Exact(_))))
+        .filter(|source| blacklist.is_match(source))
+        .map(|source| {
+            format!(
+                "`{}` is in filters.whitelist_{} but blacklisted as well",
+                source, name
+            )
+        })
+        .collect()
+}
+
+fn topic_problems(nes_config: &NesConfig) -> Vec<String> {
+    let events: Vec<NearEvent> = SAMPLE_EVENTS
+        .iter()
+        .map(|json| serde_json::from_str(json).unwrap())
+        .collect();
+
+    let mut topics = vec![nes_config.near_events_all_topic.clone()];
+    events.iter().for_each(|event| {
+        let topic = event.to_topic(&nes_config.near_events_topic_prefix);
+        topics.push(format!("{}_metadata", topic));
+        topics.push(topic);
+        nes_config
+            .routes
+            .iter()
+            .flat_map(|route| route.topics.iter().flatten())
+            .for_each(|template| topics.push(template.render(event)));
+    });
+
+    let mut invalid: Vec<String> = topics
+        .into_iter()
+        .filter(|topic| !is_valid_topic(topic))
+        .collect();
+    invalid.sort();
+    invalid.dedup();
+    invalid
+        .into_iter()
+        .map(|topic| format!("`{}` is not a valid Kafka topic name", topic))
+        .collect()
+}
+
+fn replication_problems(nes_config: &NesConfig, broker_count: usize) -> Vec<String> {
+    let replications = std::iter::once((
+        "topics.replication".to_string(),
+        nes_config.new_topic_replication,
+    ))
+    .chain(nes_config.topic_specs.iter().filter_map(|spec| {
+        spec.replication
+            .map(|replication| (format!("topics.specs `{}`", spec.pattern), replication))
+    }));
+
+    replications
+        .filter(|(_, replication)| *replication as usize > broker_count)
+        .map(|(name, replication)| {
+            format!(
+                "{} replication of {} exceeds the {} available brokers",
+                name, replication, broker_count
+            )
+        })
+        .collect()
+}
+
+fn kafka_security_problems(kafka: &HashMap<String, String>) -> Vec<String> {
+    let is_set = |key: &str| {
+        kafka
+            .get(key)
+            .map(|value| !value.is_empty())
+            .unwrap_or(false)
+    };
+    let protocol = kafka
+        .get("security.protocol")
+        .map(|protocol| protocol.to_uppercase());
+    let mechanism = kafka
+        .get("sasl.mechanisms")
+        .or_else(|| kafka.get("sasl.mechanism"))
+        .map(|mechanism| mechanism.to_uppercase());
+
+    let mut problems = vec![];
+    if !is_set("bootstrap.servers") {
+        problems.push("kafka.\"bootstrap.servers\" is empty".to_string());
+    }
+    match protocol.as_deref() {
+        Some("SASL_SSL" | "SASL_PLAINTEXT") => match mechanism.as_deref() {
+            None => problems.push("kafka.\"sasl.mechanisms\" is required with SASL".to_string()),
+            Some(mechanism) if SASL_CREDENTIAL_MECHANISMS.contains(&mechanism) => {
+                ["sasl.username", "sasl.password"]
+                    .iter()
+                    .filter(|key| !is_set(key))
+                    .for_each(|key| {
+                        problems.push(format!("kafka.\"{}\" is required with {}", key, mechanism))
+                    });
+            }
+            Some(_) => {}
+        },
+        None | Some("PLAINTEXT" | "SSL") => {
+            if kafka.keys().any(|key| key.starts_with("sasl.")) {
+                problems.push(format!(
+                    "kafka.\"sasl.*\" is set but security.protocol is {}",
+                    protocol.as_deref().unwrap_or("PLAINTEXT")
+                ));
+            }
+        }
+        Some(protocol) => problems.push(format!("Unknown kafka security.protocol {}", protocol)),
+    }
+
+    problems
+}
+
+/// Problems of a config that loaded, `broker_count` being unknown when
+/// Kafka could not be reached.
+pub fn problems(
+    nes_config: &NesConfig,
+    unknown_keys: &[String],
+    broker_count: Option<usize>,
+) -> Vec<String> {
+    let mut problems: Vec<String> = unknown_keys
+        .iter()
+        .map(|key| format!("Unknown key `{}`", key))
+        .collect();
+    problems.extend(contradictions(
+        "contract_ids",
+        &nes_config.whitelist_contract_ids,
+        &nes_config.blacklist_contract_ids,
+    ));
+    problems.extend(contradictions(
+        "predecessor_ids",
+        &nes_config.whitelist_predecessor_ids,
+        &nes_config.blacklist_predecessor_ids,
+    ));
+    problems.extend(contradictions(
+        "signer_ids",
+        &nes_config.whitelist_signer_ids,
+        &nes_config.blacklist_signer_ids,
+    ));
+    problems.extend(topic_problems(nes_config));
+    if let Some(broker_count) = broker_count {
+        problems.extend(replication_problems(nes_config, broker_count));
+    }
+    problems.extend(kafka_security_problems(&nes_config.kafka));
+
+    problems
+}
+
+fn broker_count(nes_config: &NesConfig) -> anyhow::Result<usize> {
+    let consumer: BaseConsumer = nes_config.kafka_config.create()?;
+    let metadata = consumer.fetch_metadata(None, Duration::from_secs(10))?;
+
+    Ok(metadata.brokers().len())
+}
+
+/// Loads `nes.toml` and reports the problems of every pipeline, failing
+/// when there is any.
+pub fn check_config(home_dir: PathBuf) -> anyhow::Result<()> {
+    let path = home_dir.join(NES_CONFIG_FILENAME);
+    let pipelines = NesConfig::load_pipelines(home_dir)
+        .map_err(|err| anyhow::anyhow!("{:?} is invalid: {:?}", path, err))?;
+    pipelines
+        .iter()
+        .flat_map(|(_, warnings)| warnings.deprecated_keys.iter())
+        .unique()
+        .for_each(|(key, replacement)| {
+            println!("`{}` is deprecated, use `{}` instead", key, replacement)
+        });
+    pipelines.iter().for_each(|(nes_config, warnings)| {
+        warnings.overridden_keys.iter().for_each(|key| {
+            println!(
+                "`{}` is replaced by {:?} from the admin API",
+                key, nes_config.runtime_path
+            )
+        })
+    });
+
+    let mut all_problems = vec![];
+    for (nes_config, warnings) in &pipelines {
+        let broker_count = match broker_count(nes_config) {
+            Ok(broker_count) => Some(broker_count),
+            Err(err) => {
+                println!("Could not reach Kafka, replication is not checked: {}", err);
+                None
+            }
+        };
+
+        let problems = problems(nes_config, &warnings.unknown_keys, broker_count);
+        all_problems.extend(problems.into_iter().map(|problem| match pipelines.len() {
+            1 => problem,
+            _ => format!("[{}] {}", nes_config.pipeline, problem),
+        }));
+    }
+    let problems = all_problems;
+    problems.iter().for_each(|problem| println!("{}", problem));
+    if !problems.is_empty() {
+        anyhow::bail!("{:?} has {} problem(s)", path, problems.len());
+    }
+    println!("{:?} is valid", path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reported_problems() {
+        assert!(is_valid_topic("near_events.nep171-nft_mint"));
+        assert!(!is_valid_topic("near events"));
+        assert!(!is_valid_topic(".."));
+
+        // Exact entries covered by a glob or a regex of the blacklist
+        let whitelist = PatternSet::try_from(vec![
+            "a.near".to_string(),
+            "b.paras.near".to_string(),
+            "bot1.near".to_string(),
+            "*.mintbase.near".to_string(),
+        ])
+        .unwrap();
+        let blacklist = PatternSet::try_from(vec![
+            "*.paras.near".to_string(),
+            "^bot[0-9]+\\.near$".to_string(),
+            "*.near".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(
+            contradictions("contract_ids", &whitelist, &blacklist),
+            [
+                "`a.near` is in filters.whitelist_contract_ids but blacklisted as well",
+                "`b.paras.near` is in filters.whitelist_contract_ids but blacklisted as well",
+                "`bot1.near` is in filters.whitelist_contract_ids but blacklisted as well",
+            ]
+        );
+
+        let kafka: HashMap<String, String> = [
+            ("bootstrap.servers", "localhost:9092"),
+            ("security.protocol", "SASL_SSL"),
+            ("sasl.mechanisms", "PLAIN"),
+            ("sasl.username", "nes"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(
+            kafka_security_problems(&kafka),
+            ["kafka.\"sasl.password\" is required with PLAIN"]
+        );
+    }
+}
@@ -1,11 +1,43 @@
 use std::collections::HashMap;
 
 use clap::Parser;
+use itertools::Itertools;
 use near_indexer::near_primitives::types::Gas;
 use rdkafka::config::ClientConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    enrichment::ViewCallSpec,
+    env,
+    event_types::{NearEvent, PartitionKey},
+    filter::EventFilter,
+    matcher::PatternSet,
+    routing::RouteRule,
+    sections::{self, ConfigWarnings},
+    token::{MetadataBlockFallback, PayoutSpec},
+    topics::TopicSpec,
+};
 
 pub const NES_CONFIG_FILENAME: &str = "nes.toml";
+/// Changes made through the admin API, applied over `nes.toml`
+pub const NES_RUNTIME_FILENAME: &str = "nes.runtime.json";
+/// Last block every pipeline processed
+pub const NES_CHECKPOINT_FILENAME: &str = "nes.checkpoint.json";
+/// Name of the single pipeline of a `nes.toml` without `[pipelines]`
+pub const DEFAULT_PIPELINE: &str = "default";
+/// Keys shared by all pipelines, which `[pipelines.<name>]` can't set
+const PROCESS_KEYS: [&str; 9] = [
+    "admin_addr",
+    "admin_token",
+    "admin_audit_log",
+    "config_reload_secs",
+    "metadata_cache_capacity",
+    "metadata_cache_ttl_secs",
+    "contract_metadata_cache_capacity",
+    "metadata_cache_path",
+    "metadata_cache_persist_secs",
+];
 
 #[derive(Parser, Debug)]
 #[clap(version = "0.1", author = "Sigil Network <contact@sigilnet.com>")]
@@ -27,6 +59,8 @@ pub(crate) enum SubCommand {
     Run(RunArgs),
     /// Initialize necessary configs
     Init(InitConfigArgs),
+    /// Check nes.toml, exits with an error when it has problems
+    CheckConfig,
 }
 
 #[derive(Parser, Debug)]
@@ -82,13 +116,22 @@ pub(crate) struct RunArgs {
 }
 
 impl RunArgs {
+    /// `resume_height` is the block the pipelines resume from, used instead
+    /// of the interruption point of the indexer. The pipelines still running
+    /// move that point past the blocks of a failed one.
     pub(crate) fn to_indexer_config(
         &self,
         home_dir: std::path::PathBuf,
+        resume_height: Option<u64>,
     ) -> near_indexer::IndexerConfig {
         near_indexer::IndexerConfig {
             home_dir,
-            sync_mode: self.sync_mode.clone().into(),
+            sync_mode: match (&self.sync_mode, resume_height) {
+                (SyncModeSubCommand::SyncFromInterruption, Some(height)) => {
+                    near_indexer::SyncModeEnum::BlockHeight(height)
+                }
+                (sync_mode, _) => sync_mode.clone().into(),
+            },
             await_for_node_synced: if self.stream_while_syncing {
                 near_indexer::AwaitForNodeSyncedEnum::StreamWhileSyncing
             } else {
@@ -145,36 +188,534 @@ impl From<InitConfigArgs> for near_indexer::InitConfigArgs {
     }
 }
 
+/// `nes.toml`, whose sections are flattened into these fields, see
+/// [`sections::SECTION_KEYS`]. Every key but `kafka."bootstrap.servers"`
+/// has a default.
 #[derive(Debug, Deserialize, Clone)]
 pub struct NesConfig {
+    #[serde(default)]
     pub kafka: HashMap<String, String>,
 
     #[serde(skip)]
     pub kafka_config: ClientConfig,
 
+    #[serde(default = "default_near_events_topic_prefix")]
     pub near_events_topic_prefix: String,
+    #[serde(default = "default_near_events_all_topic")]
     pub near_events_all_topic: String,
 
-    pub whitelist_contract_ids: Vec<String>,
+    #[serde(default)]
+    pub whitelist_contract_ids: PatternSet,
+    #[serde(default = "default_new_topic_partitions")]
     pub new_topic_partitions: i32,
+    #[serde(default = "default_new_topic_replication")]
     pub new_topic_replication: i32,
+    #[serde(default)]
     pub force_create_new_topic: bool,
-    pub blacklist_contract_ids: Vec<String>,
+    #[serde(default)]
+    pub blacklist_contract_ids: PatternSet,
+    /// Accounts the receipt emitting the event comes from
+    #[serde(default)]
+    pub whitelist_predecessor_ids: PatternSet,
+    #[serde(default)]
+    pub blacklist_predecessor_ids: PatternSet,
+    /// Accounts that signed the transaction behind the event
+    #[serde(default)]
+    pub whitelist_signer_ids: PatternSet,
+    #[serde(default)]
+    pub blacklist_signer_ids: PatternSet,
+    /// Only events matching one of these rules are indexed, all when empty
+    #[serde(default)]
+    pub include_events: Vec<EventFilter>,
+    #[serde(default)]
+    pub exclude_events: Vec<EventFilter>,
+    #[serde(default = "default_stats_enabled")]
     pub stats_enabled: bool,
+    #[serde(default)]
     pub enrich_metadata: bool,
+
+    #[serde(default)]
+    pub all_topic_partition_key: PartitionKey,
+    #[serde(default)]
+    pub event_topic_partition_key: PartitionKey,
+    #[serde(default)]
+    pub metadata_topic_partition_key: PartitionKey,
+
+    /// Network name available to topic templates as `{network}`
+    #[serde(default)]
+    pub network: String,
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    /// `routes` as read from `nes.toml`, before the runtime overrides
+    #[serde(skip)]
+    pub file_routes: Vec<RouteRule>,
+
+    #[serde(default)]
+    pub topic_specs: Vec<TopicSpec>,
+    /// Apply `topic_specs` to topics that already exist as well
+    #[serde(default)]
+    pub reconcile_topics: bool,
+    /// How long the known Kafka topics are trusted before being fetched again
+    #[serde(default = "default_topic_cache_refresh_secs")]
+    pub topic_cache_refresh_secs: u64,
+
+    #[serde(default = "default_metadata_cache_capacity")]
+    pub metadata_cache_capacity: usize,
+    #[serde(default = "default_metadata_cache_ttl_secs")]
+    pub metadata_cache_ttl_secs: u64,
+    /// Contracts whose `nft_metadata`/`ft_metadata` is kept in memory,
+    /// for `metadata_cache_ttl_secs` as well
+    #[serde(default = "default_contract_metadata_cache_capacity")]
+    pub contract_metadata_cache_capacity: usize,
+    /// File the metadata cache is saved to, relative to the home dir.
+    /// The cache is kept in memory only when unset.
+    pub metadata_cache_path: Option<std::path::PathBuf>,
+    #[serde(default = "default_metadata_cache_persist_secs")]
+    pub metadata_cache_persist_secs: u64,
+    /// Token metadata is read at the event block, this decides what
+    /// happens when the node no longer has the state of that block
+    #[serde(default)]
+    pub metadata_block_fallback: MetadataBlockFallback,
+    /// How many token lookups run at once for a single event
+    #[serde(default = "default_metadata_concurrency")]
+    pub metadata_concurrency: usize,
+    /// Batch views are only tried when at least this many tokens are missing
+    #[serde(default = "default_metadata_batch_threshold")]
+    pub metadata_batch_threshold: usize,
+    /// Tokens per batch call, also the page size of `nft_tokens_for_owner`
+    #[serde(default = "default_metadata_batch_size")]
+    pub metadata_batch_size: usize,
+    #[serde(default = "default_metadata_batch_owner_pages")]
+    pub metadata_batch_owner_pages: usize,
+    /// Contract to a view method taking `{"token_ids": [...]}` and returning
+    /// the tokens, for contracts that offer one
+    #[serde(default)]
+    pub metadata_batch_methods: HashMap<String, String>,
+
+    /// Attach the account state of the owners in NFT events
+    #[serde(default)]
+    pub enrich_accounts: bool,
+    #[serde(default)]
+    pub view_calls: Vec<ViewCallSpec>,
+    /// Contracts whose transfers get their NEP-199 payout attached
+    #[serde(default)]
+    pub payouts: Vec<PayoutSpec>,
+    /// Fetch the off-chain `reference` JSON of token metadata
+    #[serde(default)]
+    pub resolve_references: bool,
+    #[serde(default = "default_reference_ipfs_gateway")]
+    pub reference_ipfs_gateway: String,
+    #[serde(default = "default_reference_timeout_ms")]
+    pub reference_timeout_ms: u64,
+    /// References larger than this are not resolved
+    #[serde(default = "default_reference_max_bytes")]
+    pub reference_max_bytes: usize,
+    #[serde(default = "default_reference_cache_capacity")]
+    pub reference_cache_capacity: usize,
+
+    /// Address of the admin HTTP API, e.g. `127.0.0.1:3030`. Disabled when unset.
+    pub admin_addr: Option<String>,
+    /// Bearer token required by the admin API when set
+    pub admin_token: Option<String>,
+    /// JSON lines file recording every change made through the admin API,
+    /// relative to the home dir
+    #[serde(default = "default_admin_audit_log")]
+    pub admin_audit_log: std::path::PathBuf,
+    #[serde(skip)]
+    pub runtime_path: std::path::PathBuf,
+
+    /// How often `nes.toml` is checked for changes, 0 to only reload on SIGHUP
+    #[serde(default = "default_config_reload_secs")]
+    pub config_reload_secs: u64,
+    #[serde(skip)]
+    pub home_dir: std::path::PathBuf,
+    /// Name of the `[pipelines.<name>]` table this config comes from
+    #[serde(skip)]
+    pub pipeline: String,
+    #[serde(skip)]
+    pub checkpoint_path: std::path::PathBuf,
+}
+
+/// Filters and routes changed at runtime, saved to [`NES_RUNTIME_FILENAME`].
+/// Set fields replace the ones of `nes.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuntimeOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub whitelist_contract_ids: Option<PatternSet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blacklist_contract_ids: Option<PatternSet>,
+    /// Routes added after the ones of `nes.toml`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added_routes: Vec<RouteRule>,
+    /// Ids of the `nes.toml` routes that are dropped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_routes: Vec<String>,
+}
+
+impl RuntimeOverrides {
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// `nes.toml` keys overridden, `sinks.routes` when some of its routes
+    /// are removed.
+    pub fn keys(&self) -> Vec<&'static str> {
+        [
+            (
+                self.whitelist_contract_ids.is_some(),
+                "filters.whitelist_contract_ids",
+            ),
+            (
+                self.blacklist_contract_ids.is_some(),
+                "filters.blacklist_contract_ids",
+            ),
+            (!self.removed_routes.is_empty(), "sinks.routes"),
+        ]
+        .into_iter()
+        .filter_map(|(set, key)| set.then_some(key))
+        .collect()
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let file = std::fs::File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+fn default_near_events_topic_prefix() -> String {
+    String::from("near_events")
+}
+
+fn default_near_events_all_topic() -> String {
+    String::from("near_events_all")
+}
+
+fn default_new_topic_partitions() -> i32 {
+    1
+}
+
+fn default_new_topic_replication() -> i32 {
+    1
+}
+
+fn default_stats_enabled() -> bool {
+    true
+}
+
+fn default_topic_cache_refresh_secs() -> u64 {
+    300
+}
+
+fn default_metadata_cache_capacity() -> usize {
+    100_000
+}
+
+fn default_metadata_cache_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_contract_metadata_cache_capacity() -> usize {
+    10_000
+}
+
+fn default_metadata_cache_persist_secs() -> u64 {
+    60
+}
+
+fn default_metadata_concurrency() -> usize {
+    8
+}
+
+fn default_metadata_batch_threshold() -> usize {
+    10
+}
+
+fn default_metadata_batch_size() -> usize {
+    100
+}
+
+fn default_metadata_batch_owner_pages() -> usize {
+    10
+}
+
+fn default_reference_ipfs_gateway() -> String {
+    String::from("https://ipfs.io/ipfs")
+}
+
+fn default_reference_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_reference_max_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_reference_cache_capacity() -> usize {
+    10_000
+}
+
+fn default_config_reload_secs() -> u64 {
+    10
+}
+
+fn default_admin_audit_log() -> std::path::PathBuf {
+    std::path::PathBuf::from("nes.audit.log")
+}
+
+/// `file_name` of `pipeline`, `nes.runtime.json` becoming
+/// `nes.runtime.<pipeline>.json` for the named pipelines.
+fn pipeline_path(
+    home_dir: &std::path::Path,
+    file_name: &str,
+    pipeline: &str,
+) -> std::path::PathBuf {
+    match (pipeline, file_name.rsplit_once('.')) {
+        (DEFAULT_PIPELINE, _) | (_, None) => home_dir.join(file_name),
+        (_, Some((stem, extension))) => {
+            home_dir.join(format!("{}.{}.{}", stem, pipeline, extension))
+        }
+    }
+}
+
+/// Merges `overrides` into `base`, tables key by key.
+fn merge_toml(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_toml(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn is_allowed(whitelist: &PatternSet, blacklist: &PatternSet, account_id: Option<&str>) -> bool {
+    match account_id {
+        Some(account_id) => {
+            (whitelist.is_empty() || whitelist.is_match(account_id))
+                && !blacklist.is_match(account_id)
+        }
+        None => whitelist.is_empty(),
+    }
 }
 
 impl NesConfig {
-    pub fn new(home_dir: std::path::PathBuf) -> anyhow::Result<Self> {
+    /// The config of every pipeline, logging the unknown and deprecated keys.
+    pub fn pipelines(home_dir: std::path::PathBuf) -> anyhow::Result<Vec<Self>> {
+        let pipelines = Self::load_pipelines(home_dir)?;
+        pipelines
+            .iter()
+            .flat_map(|(_, warnings)| warnings.unknown_keys.iter())
+            .unique()
+            .for_each(|key| warn!("Unknown key `{}` in {}", key, NES_CONFIG_FILENAME));
+        pipelines
+            .iter()
+            .flat_map(|(_, warnings)| warnings.deprecated_keys.iter())
+            .unique()
+            .for_each(|(key, replacement)| {
+                warn!(
+                    "`{}` is deprecated in {}, use `{}` instead",
+                    key, NES_CONFIG_FILENAME, replacement
+                )
+            });
+        pipelines.iter().for_each(|(nes_conf, warnings)| {
+            warnings.overridden_keys.iter().for_each(|key| {
+                warn!(
+                    "`{}` of {} is overridden by {:?} for pipeline `{}`, DELETE /config/overrides on the admin API drops it",
+                    key, NES_CONFIG_FILENAME, nes_conf.runtime_path, nes_conf.pipeline
+                )
+            })
+        });
+
+        Ok(pipelines
+            .into_iter()
+            .map(|(nes_conf, _)| nes_conf)
+            .collect())
+    }
+
+    /// Loads one config per `[pipelines.<name>]` table of `nes.toml`,
+    /// that table being merged over the top-level keys. Without pipelines,
+    /// the top-level keys make the `default` one.
+    pub fn load_pipelines(
+        home_dir: std::path::PathBuf,
+    ) -> anyhow::Result<Vec<(Self, ConfigWarnings)>> {
         let conf_file = home_dir.join(NES_CONFIG_FILENAME);
+        let source = std::fs::read_to_string(&conf_file)
+            .map_err(|err| anyhow::anyhow!("Could not read {:?}: {}", conf_file, err))?;
+        let mut source: toml::Value = toml::from_str(&source)?;
+
+        let pipelines = match source
+            .as_table_mut()
+            .and_then(|table| table.remove("pipelines"))
+        {
+            None => return Ok(vec![Self::load(DEFAULT_PIPELINE, source, &home_dir)?]),
+            Some(toml::Value::Table(pipelines)) if !pipelines.is_empty() => pipelines,
+            Some(_) => anyhow::bail!("pipelines must hold [pipelines.<name>] tables"),
+        };
+        pipelines
+            .into_iter()
+            .map(|(name, overrides)| {
+                if !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+                {
+                    anyhow::bail!(
+                        "Pipeline name `{}` can only have letters, digits, _ and -",
+                        name
+                    );
+                }
+                let mut flat_overrides = overrides.clone();
+                sections::flatten(&mut flat_overrides);
+                if let Some(key) = PROCESS_KEYS
+                    .iter()
+                    .find(|key| flat_overrides.get(**key).is_some())
+                {
+                    anyhow::bail!(
+                        "`{}` is shared by the pipelines, it can't be set in [pipelines.{}]",
+                        key,
+                        name
+                    );
+                }
+                let mut source = source.clone();
+                merge_toml(&mut source, overrides);
+                Self::load(&name, source, &home_dir)
+                    .map_err(|err| anyhow::anyhow!("Pipeline `{}`: {:?}", name, err))
+            })
+            .collect()
+    }
+
+    /// Loads the config of `pipeline`, along with its unknown and deprecated
+    /// keys. Sections are flattened first, then the environment overrides,
+    /// interpolations and secret files are applied.
+    fn load(
+        pipeline: &str,
+        mut source: toml::Value,
+        home_dir: &std::path::Path,
+    ) -> anyhow::Result<(Self, ConfigWarnings)> {
+        let mut warnings = sections::flatten(&mut source);
+        env::apply_env_overrides(&mut source, std::env::vars());
+        env::resolve_values(&mut source, home_dir, &|name| std::env::var(name).ok())?;
+
         let conf = config::Config::builder()
-            .add_source(config::File::from(conf_file))
+            .add_source(config::File::from_str(
+                &toml::to_string(&source)?,
+                config::FileFormat::Toml,
+            ))
             .build()?;
-
-        let mut nes_conf = conf.try_deserialize::<Self>()?;
+        let mut nes_conf: Self =
+            serde_ignored::deserialize(conf, |path| warnings.unknown_keys.push(path.to_string()))?;
+        nes_conf.pipeline = pipeline.to_string();
         nes_conf.init_kafka_config();
+        nes_conf.init_paths(home_dir);
+        nes_conf.file_routes = nes_conf.routes.clone();
+        let overrides = RuntimeOverrides::load(&nes_conf.runtime_path)?;
+        warnings.overridden_keys = overrides.keys().into_iter().map(String::from).collect();
+        nes_conf.apply_overrides(overrides);
+        nes_conf.validate()?;
+
+        Ok((nes_conf, warnings))
+    }
+
+    /// Checks what deserializing alone can't, so that a bad config is
+    /// rejected before any block is processed with it.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // An empty topics.prefix is fine, the sample used to ship one
+        if self.near_events_all_topic.is_empty() {
+            anyhow::bail!("topics.all_topic can't be empty");
+        }
+        if !self.kafka.contains_key("bootstrap.servers") {
+            anyhow::bail!("kafka.\"bootstrap.servers\" is missing");
+        }
+        if self.new_topic_partitions < 1 || self.new_topic_replication < 1 {
+            anyhow::bail!("topics.partitions and topics.replication must be at least 1");
+        }
+        if self.metadata_concurrency == 0 || self.metadata_batch_size == 0 {
+            anyhow::bail!("enrichment.metadata_concurrency and enrichment.metadata_batch_size must be at least 1");
+        }
+
+        Ok(())
+    }
+
+    /// Replaces filters with the ones set in `overrides`, and routes with
+    /// the `nes.toml` ones less the removed ones, followed by the added ones.
+    pub fn apply_overrides(&mut self, overrides: RuntimeOverrides) {
+        if let Some(whitelist) = overrides.whitelist_contract_ids {
+            self.whitelist_contract_ids = whitelist;
+        }
+        if let Some(blacklist) = overrides.blacklist_contract_ids {
+            self.blacklist_contract_ids = blacklist;
+        }
+        self.routes = self
+            .file_routes
+            .iter()
+            .filter(|route| !overrides.removed_routes.contains(&route.id()))
+            .cloned()
+            .chain(overrides.added_routes)
+            .collect();
+        self.init_routes();
+    }
+
+    pub fn topic_spec(&self, topic: &str) -> Option<&TopicSpec> {
+        self.topic_specs
+            .iter()
+            .find(|spec| spec.pattern.is_match(topic))
+    }
+
+    /// Whether events of this contract pass the whitelist and blacklist.
+    pub fn is_contract_allowed(&self, contract_account_id: &str) -> bool {
+        is_allowed(
+            &self.whitelist_contract_ids,
+            &self.blacklist_contract_ids,
+            Some(contract_account_id),
+        )
+    }
+
+    /// Whether events of a receipt pass the predecessor and signer lists.
+    /// Receipts without a signer (data receipts) only pass when there is
+    /// no signer whitelist.
+    pub fn is_receipt_allowed(&self, predecessor_id: &str, signer_id: Option<&str>) -> bool {
+        is_allowed(
+            &self.whitelist_predecessor_ids,
+            &self.blacklist_predecessor_ids,
+            Some(predecessor_id),
+        ) && is_allowed(
+            &self.whitelist_signer_ids,
+            &self.blacklist_signer_ids,
+            signer_id,
+        )
+    }
 
-        Ok(nes_conf)
+    /// Whether the event passes `include_events` and `exclude_events`.
+    pub fn is_event_allowed(&self, event: &NearEvent) -> bool {
+        (self.include_events.is_empty()
+            || self
+                .include_events
+                .iter()
+                .any(|filter| filter.matches(event)))
+            && !self
+                .exclude_events
+                .iter()
+                .any(|filter| filter.matches(event))
+    }
+
+    pub fn payout_spec(&self, contract_account_id: &str) -> Option<&PayoutSpec> {
+        self.payouts
+            .iter()
+            .find(|spec| spec.contract.is_match(contract_account_id))
     }
 
     fn init_kafka_config(&mut self) {
@@ -184,4 +725,25 @@ impl NesConfig {
         });
         self.kafka_config = kafka_conf;
     }
+
+    fn init_paths(&mut self, home_dir: &std::path::Path) {
+        if let Some(path) = &self.metadata_cache_path {
+            self.metadata_cache_path = Some(home_dir.join(path));
+        }
+        self.admin_audit_log = home_dir.join(&self.admin_audit_log);
+        self.runtime_path = pipeline_path(home_dir, NES_RUNTIME_FILENAME, &self.pipeline);
+        self.checkpoint_path = pipeline_path(home_dir, NES_CHECKPOINT_FILENAME, &self.pipeline);
+        self.home_dir = home_dir.to_path_buf();
+    }
+
+    fn init_routes(&mut self) {
+        let network = self.network.clone();
+        let prefix = self.near_events_topic_prefix.clone();
+        self.routes
+            .iter_mut()
+            .for_each(|route| route.bind(&network, &prefix));
+        self.view_calls
+            .iter_mut()
+            .for_each(|view_call| view_call.bind(&network, &prefix));
+    }
 }
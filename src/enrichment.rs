@@ -0,0 +1,674 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use futures::{stream::FuturesOrdered, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::{
+    configs::NesConfig,
+    event_types::{EventData, NearEvent, Nep171Data},
+    matcher::Pattern,
+    resolver::ReferenceResolver,
+    template::JsonTemplate,
+    token::{get_metadatas, ContractMetadata, Payout, TokenClient},
+};
+
+/// Events an enricher handles, matched on their standard and event name.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventTarget {
+    pub standard: Pattern,
+    pub event: Pattern,
+}
+
+impl EventTarget {
+    fn new(standard: &str, event: &str) -> anyhow::Result<Self> {
+        let parse = |pattern: &str| {
+            pattern
+                .parse()
+                .map_err(|err| anyhow::anyhow!("Invalid enricher target `{}`: {:?}", pattern, err))
+        };
+
+        Ok(Self {
+            standard: parse(standard)?,
+            event: parse(event)?,
+        })
+    }
+
+    pub fn matches(&self, event: &NearEvent) -> bool {
+        self.standard.is_match(&event.standard) && self.event.is_match(&event.event)
+    }
+}
+
+/// A view call from the `[[view_calls]]` table of `nes.toml`: for events
+/// matching `contract`, `standard` and `event`, `method` is called on the
+/// contract with `args` and its result is attached under `key`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ViewCallSpec {
+    pub key: String,
+    pub contract: Option<Pattern>,
+    #[serde(default = "default_pattern")]
+    pub standard: Pattern,
+    #[serde(default = "default_pattern")]
+    pub event: Pattern,
+    pub method: String,
+    /// JSON arguments whose strings are templates, e.g.
+    /// `{"token_id": "{token_id}"}`. Written as a JSON string in `nes.toml`
+    /// so argument names keep their case.
+    #[serde(default = "default_args")]
+    pub args: JsonTemplate,
+}
+
+fn default_pattern() -> Pattern {
+    "*".parse().unwrap()
+}
+
+fn default_args() -> JsonTemplate {
+    JsonTemplate::Object(vec![])
+}
+
+impl ViewCallSpec {
+    pub(crate) fn bind(&mut self, network: &str, prefix: &str) {
+        self.args = self.args.bind(network, prefix);
+    }
+}
+
+/// What enrichers know about the event being enriched.
+pub struct EnrichContext<'a> {
+    pub nes_config: &'a NesConfig,
+    pub token_client: &'a TokenClient,
+    pub contract_account_id: &'a str,
+    pub block_height: u64,
+}
+
+/// One step of the enrichment of the events sent to the `_metadata` topics.
+/// Enrichers get the flattened events of one emitted event, or the event
+/// itself when it has nothing to flatten, and add their findings to the
+/// `enrichment` object of each, under their own name.
+#[async_trait(?Send)]
+pub trait Enricher {
+    fn name(&self) -> &str;
+
+    fn targets(&self) -> &[EventTarget];
+
+    async fn enrich(&self, ctx: &EnrichContext<'_>, events: &mut [NearEvent])
+        -> anyhow::Result<()>;
+}
+
+/// The registered enrichers, run in order so later ones can read what the
+/// earlier ones found.
+pub struct EnricherChain {
+    token_client: Arc<TokenClient>,
+    enrichers: Vec<Box<dyn Enricher>>,
+}
+
+impl EnricherChain {
+    pub fn new(nes_config: &NesConfig, token_client: Arc<TokenClient>) -> anyhow::Result<Self> {
+        let mut enrichers: Vec<Box<dyn Enricher>> = vec![
+            Box::new(ContractMetadataEnricher::new()?),
+            Box::new(TokenMetadataEnricher::new()?),
+        ];
+        if !nes_config.payouts.is_empty() {
+            enrichers.push(Box::new(PayoutEnricher::new()?));
+        }
+        if nes_config.enrich_accounts {
+            enrichers.push(Box::new(AccountEnricher::new()?));
+        }
+        enrichers.extend(
+            nes_config
+                .view_calls
+                .iter()
+                .map(|spec| Box::new(ViewCallEnricher::new(spec.clone())) as Box<dyn Enricher>),
+        );
+        if nes_config.resolve_references {
+            enrichers.push(Box::new(ReferenceEnricher::new(ReferenceResolver::new(
+                nes_config,
+            ))?));
+        }
+
+        Ok(Self {
+            token_client,
+            enrichers,
+        })
+    }
+
+    pub fn token_client(&self) -> &TokenClient {
+        &self.token_client
+    }
+
+    /// Flattens `event` and runs the enrichers targeting it. Events with
+    /// nothing to flatten, like NEP-141 ones, are enriched as they are when
+    /// an enricher targets them, and dropped otherwise.
+    pub async fn enrich(
+        &self,
+        nes_config: &NesConfig,
+        event: &NearEvent,
+    ) -> anyhow::Result<Vec<NearEvent>> {
+        let emit_info = match &event.emit_info {
+            Some(emit_info) => emit_info,
+            None => return Ok(vec![]),
+        };
+        let ctx = EnrichContext {
+            nes_config,
+            token_client: &self.token_client,
+            contract_account_id: &emit_info.contract_account_id,
+            block_height: emit_info.block_height,
+        };
+
+        let enrichers: Vec<&dyn Enricher> = self
+            .enrichers
+            .iter()
+            .map(Box::as_ref)
+            .filter(|enricher| {
+                enricher
+                    .targets()
+                    .iter()
+                    .any(|target| target.matches(event))
+            })
+            .collect();
+
+        let mut events = event.try_flatten_nep171_event();
+        if events.is_empty() && !enrichers.is_empty() {
+            events.push(event.clone());
+        }
+        for enricher in enrichers {
+            enricher.enrich(&ctx, &mut events).await?;
+        }
+
+        Ok(events)
+    }
+}
+
+/// `nft_metadata`/`ft_metadata` of the contract, as `contract_metadata`.
+pub struct ContractMetadataEnricher {
+    targets: Vec<EventTarget>,
+}
+
+impl ContractMetadataEnricher {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            targets: vec![
+                EventTarget::new("nep171", "*")?,
+                EventTarget::new("nep141", "*")?,
+            ],
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Enricher for ContractMetadataEnricher {
+    fn name(&self) -> &str {
+        "contract_metadata"
+    }
+
+    fn targets(&self) -> &[EventTarget] {
+        &self.targets
+    }
+
+    async fn enrich(
+        &self,
+        ctx: &EnrichContext<'_>,
+        events: &mut [NearEvent],
+    ) -> anyhow::Result<()> {
+        let event = match events.first() {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        let contract_metadata = ctx
+            .token_client
+            .get_contract_metadata(ctx.nes_config, event)
+            .await?;
+
+        if let Some(contract_metadata) = contract_metadata {
+            let value = serde_json::to_value(contract_metadata)?;
+            for event in events.iter_mut() {
+                event
+                    .enrichment
+                    .insert(self.name().to_string(), value.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Token metadata of NEP-171 mints and transfers. These fill the metadata
+/// fields of the event data, which predate the `enrichment` object.
+pub struct TokenMetadataEnricher {
+    targets: Vec<EventTarget>,
+}
+
+impl TokenMetadataEnricher {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            targets: vec![
+                EventTarget::new("nep171", "nft_mint")?,
+                EventTarget::new("nep171", "nft_transfer")?,
+            ],
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Enricher for TokenMetadataEnricher {
+    fn name(&self) -> &str {
+        "token_metadata"
+    }
+
+    fn targets(&self) -> &[EventTarget] {
+        &self.targets
+    }
+
+    async fn enrich(
+        &self,
+        ctx: &EnrichContext<'_>,
+        events: &mut [NearEvent],
+    ) -> anyhow::Result<()> {
+        // Tokens of the same owner are looked up together, so contracts
+        // enumerating tokens per owner can answer them in a few calls
+        let token_ids_by_owner = events
+            .iter()
+            .flat_map(|event| {
+                let owner_id = event.owner_id();
+                event
+                    .token_ids()
+                    .into_iter()
+                    .map(move |token_id| (owner_id.clone(), token_id))
+            })
+            .into_group_map();
+
+        let mut tokens = HashMap::new();
+        for (owner_id, token_ids) in token_ids_by_owner.iter() {
+            tokens.extend(
+                ctx.token_client
+                    .get_nft_tokens(
+                        ctx.nes_config,
+                        ctx.contract_account_id,
+                        owner_id.as_deref(),
+                        token_ids,
+                        ctx.block_height,
+                    )
+                    .await?,
+            );
+        }
+
+        for event in events.iter_mut() {
+            match event.data {
+                EventData::Nep171(Nep171Data::MintFlat(ref mut data)) => {
+                    let metadatas =
+                        get_metadatas(ctx.contract_account_id, &data.token_ids, &tokens);
+
+                    data._ids = Some(metadatas._ids);
+                    data.metadatas = Some(metadatas.metadatas);
+                    data.metadata_extras = Some(metadatas.extras);
+                    data.metadata_sources = Some(metadatas.sources);
+                    data.metadata_statuses = Some(metadatas.statuses);
+                }
+                EventData::Nep171(Nep171Data::TransferFlat(ref mut data)) => {
+                    let metadatas =
+                        get_metadatas(ctx.contract_account_id, &data.token_ids, &tokens);
+
+                    data._ids = Some(metadatas._ids);
+                    data.metadatas = Some(metadatas.metadatas);
+                    data.metadata_extras = Some(metadatas.extras);
+                    data.metadata_sources = Some(metadatas.sources);
+                    data.metadata_statuses = Some(metadatas.statuses);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// NEP-199 payout of transferred tokens, as `payouts`, for the contracts
+/// listed in `[[payouts]]`.
+pub struct PayoutEnricher {
+    targets: Vec<EventTarget>,
+}
+
+impl PayoutEnricher {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            targets: vec![EventTarget::new("nep171", "nft_transfer")?],
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Enricher for PayoutEnricher {
+    fn name(&self) -> &str {
+        "payouts"
+    }
+
+    fn targets(&self) -> &[EventTarget] {
+        &self.targets
+    }
+
+    async fn enrich(
+        &self,
+        ctx: &EnrichContext<'_>,
+        events: &mut [NearEvent],
+    ) -> anyhow::Result<()> {
+        let spec = match ctx.nes_config.payout_spec(ctx.contract_account_id) {
+            Some(spec) => spec,
+            None => return Ok(()),
+        };
+
+        for event in events.iter_mut() {
+            let payouts = event
+                .token_ids()
+                .iter()
+                .map(|token_id| {
+                    ctx.token_client.get_nft_payout(
+                        ctx.nes_config,
+                        spec,
+                        ctx.contract_account_id,
+                        token_id,
+                        ctx.block_height,
+                    )
+                })
+                .collect::<FuturesOrdered<_>>()
+                .try_collect::<Vec<Option<Payout>>>()
+                .await?;
+            event
+                .enrichment
+                .insert(self.name().to_string(), serde_json::to_value(payouts)?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Account state (balance, storage, code hash) of the owners involved in
+/// NFT events, as `accounts` keyed by account id.
+pub struct AccountEnricher {
+    targets: Vec<EventTarget>,
+}
+
+impl AccountEnricher {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            targets: vec![
+                EventTarget::new("nep171", "nft_mint")?,
+                EventTarget::new("nep171", "nft_transfer")?,
+                EventTarget::new("nep171", "nft_burn")?,
+            ],
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Enricher for AccountEnricher {
+    fn name(&self) -> &str {
+        "accounts"
+    }
+
+    fn targets(&self) -> &[EventTarget] {
+        &self.targets
+    }
+
+    async fn enrich(
+        &self,
+        ctx: &EnrichContext<'_>,
+        events: &mut [NearEvent],
+    ) -> anyhow::Result<()> {
+        // The flattened events of a mint share their owner, which is
+        // looked up once
+        let account_ids: Vec<String> = events
+            .iter()
+            .flat_map(NearEvent::account_ids)
+            .unique()
+            .collect();
+        let accounts = account_ids
+            .iter()
+            .map(|account_id| {
+                ctx.token_client
+                    .view_account(ctx.nes_config, account_id, ctx.block_height)
+            })
+            .collect::<FuturesOrdered<_>>()
+            .try_collect::<Vec<_>>()
+            .await?;
+        let accounts: HashMap<String, serde_json::Value> = account_ids
+            .into_iter()
+            .zip(accounts)
+            .filter_map(|(account_id, account)| Some((account_id, account?)))
+            .map(|(account_id, account)| Ok((account_id, serde_json::to_value(account)?)))
+            .collect::<anyhow::Result<_>>()?;
+
+        for event in events.iter_mut() {
+            let values = event
+                .account_ids()
+                .into_iter()
+                .filter_map(|account_id| {
+                    let account = accounts.get(&account_id)?.clone();
+                    Some((account_id, account))
+                })
+                .collect();
+            event
+                .enrichment
+                .insert(self.name().to_string(), serde_json::Value::Object(values));
+        }
+
+        Ok(())
+    }
+}
+
+/// Configured view call, see [`ViewCallSpec`].
+pub struct ViewCallEnricher {
+    targets: Vec<EventTarget>,
+    spec: ViewCallSpec,
+}
+
+impl ViewCallEnricher {
+    pub fn new(spec: ViewCallSpec) -> Self {
+        Self {
+            targets: vec![EventTarget {
+                standard: spec.standard.clone(),
+                event: spec.event.clone(),
+            }],
+            spec,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Enricher for ViewCallEnricher {
+    fn name(&self) -> &str {
+        &self.spec.key
+    }
+
+    fn targets(&self) -> &[EventTarget] {
+        &self.targets
+    }
+
+    async fn enrich(
+        &self,
+        ctx: &EnrichContext<'_>,
+        events: &mut [NearEvent],
+    ) -> anyhow::Result<()> {
+        if let Some(contract) = &self.spec.contract {
+            if !contract.is_match(ctx.contract_account_id) {
+                return Ok(());
+            }
+        }
+
+        let results = events
+            .iter()
+            .map(|event| {
+                ctx.token_client.view_json(
+                    ctx.nes_config,
+                    ctx.contract_account_id,
+                    &self.spec.method,
+                    self.spec.args.render(event),
+                    ctx.block_height,
+                )
+            })
+            .collect::<FuturesOrdered<_>>()
+            .try_collect::<Vec<Option<serde_json::Value>>>()
+            .await?;
+
+        for (event, result) in events.iter_mut().zip(results) {
+            if let Some(result) = result {
+                event.enrichment.insert(self.name().to_string(), result);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Off-chain `reference` JSON of each token, as `references`. Relative
+/// references use the `base_uri` found by [`ContractMetadataEnricher`].
+pub struct ReferenceEnricher {
+    targets: Vec<EventTarget>,
+    resolver: ReferenceResolver,
+}
+
+impl ReferenceEnricher {
+    pub fn new(resolver: ReferenceResolver) -> anyhow::Result<Self> {
+        Ok(Self {
+            targets: vec![
+                EventTarget::new("nep171", "nft_mint")?,
+                EventTarget::new("nep171", "nft_transfer")?,
+            ],
+            resolver,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Enricher for ReferenceEnricher {
+    fn name(&self) -> &str {
+        "references"
+    }
+
+    fn targets(&self) -> &[EventTarget] {
+        &self.targets
+    }
+
+    async fn enrich(
+        &self,
+        _ctx: &EnrichContext<'_>,
+        events: &mut [NearEvent],
+    ) -> anyhow::Result<()> {
+        for event in events.iter_mut() {
+            let base_uri = event
+                .enrichment
+                .get("contract_metadata")
+                .cloned()
+                .and_then(|value| serde_json::from_value::<ContractMetadata>(value).ok())
+                .and_then(|contract_metadata| match contract_metadata {
+                    ContractMetadata::Nft(metadata) => metadata.base_uri,
+                    ContractMetadata::Ft(_) => None,
+                });
+            let metadatas = match &event.data {
+                EventData::Nep171(Nep171Data::MintFlat(data)) => data.metadatas.clone(),
+                EventData::Nep171(Nep171Data::TransferFlat(data)) => data.metadatas.clone(),
+                _ => None,
+            };
+            let metadatas = match metadatas {
+                Some(metadatas) => metadatas,
+                None => continue,
+            };
+
+            let references = metadatas
+                .iter()
+                .map(|metadata| {
+                    let base_uri = base_uri.as_deref();
+                    async move {
+                        match metadata {
+                            Some(metadata) => {
+                                self.resolver.resolve_metadata(metadata, base_uri).await
+                            }
+                            None => None,
+                        }
+                    }
+                })
+                .collect::<FuturesOrdered<_>>()
+                .collect::<Vec<Option<serde_json::Value>>>()
+                .await;
+            event
+                .enrichment
+                .insert(self.name().to_string(), serde_json::to_value(references)?);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::token::{ContractMetadataCache, FtContractMetadata, TokenCache};
+
+    use super::*;
+
+    #[test]
+    fn targets() {
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"old_owner_id":"a.testnet","new_owner_id":"b.testnet","token_ids":["7"]}]}"#;
+        let event: NearEvent = serde_json::from_str(json).unwrap();
+
+        assert!(EventTarget::new("nep171", "*").unwrap().matches(&event));
+        assert!(EventTarget::new("nep171", "nft_transfer")
+            .unwrap()
+            .matches(&event));
+        assert!(!EventTarget::new("nep171", "nft_mint")
+            .unwrap()
+            .matches(&event));
+        assert!(!EventTarget::new("nep141", "*").unwrap().matches(&event));
+        assert!(event.enrichment.is_empty());
+        assert!(EventTarget::new("nep171", "^(").is_err());
+    }
+
+    #[test]
+    fn enriches_unflattened_events() {
+        let json = r#"{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{"old_owner_id":"a.near","new_owner_id":"b.near","amount":"1000000"}],"emit_info":{"receipt_id":"receipt","block_timestamp":0,"block_height":1,"shard_id":0,"contract_account_id":"usdc.near","predecessor_id":"a.near"}}"#;
+        let event: NearEvent = serde_json::from_str(json).unwrap();
+        let nes_config: NesConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        let ft_metadata = FtContractMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "USD Coin".to_string(),
+            symbol: "USDC".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 6,
+        };
+
+        actix::System::new().block_on(async {
+            // The contract metadata is cached, the view client is never called
+            let (sender, _receiver) = actix::dev::channel::channel(1);
+            let contract_cache = ContractMetadataCache::new(1, Duration::from_secs(60));
+            contract_cache.insert(
+                "usdc.near".to_string(),
+                (1, ContractMetadata::Ft(ft_metadata.clone())),
+            );
+            let token_client = TokenClient::new(
+                actix::Addr::new(sender),
+                Arc::new(TokenCache::new(1, Duration::from_secs(60))),
+                contract_cache,
+            );
+            let chain = EnricherChain::new(&nes_config, Arc::new(token_client)).unwrap();
+
+            let events = chain.enrich(&nes_config, &event).await.unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event, "ft_transfer");
+            assert_eq!(
+                events[0].enrichment["contract_metadata"],
+                serde_json::to_value(ft_metadata).unwrap()
+            );
+
+            // No enricher targets it, nothing goes to the metadata topics
+            let mut unknown = event.clone();
+            unknown.standard = "nep999".to_string();
+            assert!(chain
+                .enrich(&nes_config, &unknown)
+                .await
+                .unwrap()
+                .is_empty());
+        });
+    }
+}
@@ -0,0 +1,272 @@
+use std::path::Path;
+
+use toml::Value;
+
+use crate::sections::{SECTION_KEYS, TOP_LEVEL_KEYS};
+
+/// Prefix of the environment variables overriding `nes.toml` keys
+pub const ENV_PREFIX: &str = "NES_";
+/// Suffix of the keys whose value is read from a file
+pub const FILE_SUFFIX: &str = "_file";
+/// Top-level keys, besides the `kafka` properties, that can be set from
+/// the environment with `${VAR}` or from a file with `<key>_file`
+const SECRET_KEYS: [&str; 1] = ["admin_token"];
+/// Fields holding a list of patterns, set from comma separated values
+const LIST_FIELDS: [&str; 6] = [
+    "whitelist_contract_ids",
+    "blacklist_contract_ids",
+    "whitelist_predecessor_ids",
+    "blacklist_predecessor_ids",
+    "whitelist_signer_ids",
+    "blacklist_signer_ids",
+];
+
+/// Expands `${VAR}` and `${VAR:-default}` in `source`, `$$` being a literal `$`.
+pub fn interpolate(source: &str, vars: &impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(source.len());
+    let mut rest = source;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unclosed `${{` in `{}`", source))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            let value = vars(name)
+                .or_else(|| default.map(str::to_string))
+                .ok_or_else(|| anyhow::anyhow!("Environment variable {} is not set", name))?;
+            result.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// `nes.toml` key set by a `NES_` variable. `NES_STATS_ENABLED` sets
+/// `[stats] enabled`, `NES_TOPICS_PREFIX` sets `[topics] prefix` and,
+/// since Kafka properties are dotted, `NES_KAFKA_SASL_PASSWORD` sets
+/// `kafka."sasl.password"`. In Kafka properties `__` stands for an
+/// underscore. The deprecated top-level names work as well, with a `_FILE`
+/// suffix too, other variables like `NES_SERVICE_HOST` are left alone.
+fn env_key(name: &str) -> Option<(bool, String)> {
+    let key = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+    if let Some(property) = key.strip_prefix("kafka_") {
+        return Some((
+            true,
+            property
+                .split("__")
+                .map(|part| part.replace('_', "."))
+                .collect::<Vec<_>>()
+                .join("_"),
+        ));
+    }
+
+    let (key, suffix) = match key.strip_suffix(FILE_SUFFIX) {
+        Some(key) => (key, FILE_SUFFIX),
+        None => (key.as_str(), ""),
+    };
+    let field = SECTION_KEYS
+        .iter()
+        .find(|(section, section_key, field)| {
+            key == format!("{}_{}", section, section_key) || key == *field
+        })
+        .map(|(_, _, field)| *field)
+        .or_else(|| TOP_LEVEL_KEYS.iter().find(|field| key == **field).copied())?;
+
+    Some((false, format!("{}{}", field, suffix)))
+}
+
+/// Overrides the keys of `config`, with its sections flattened, by the
+/// `NES_` variables. Pattern lists take comma separated values.
+pub fn apply_env_overrides(config: &mut Value, vars: impl Iterator<Item = (String, String)>) {
+    let table = match config.as_table_mut() {
+        Some(table) => table,
+        None => return,
+    };
+
+    for (name, value) in vars {
+        match env_key(&name) {
+            Some((true, property)) => {
+                if let Some(kafka) = table
+                    .entry("kafka")
+                    .or_insert_with(|| Value::Table(Default::default()))
+                    .as_table_mut()
+                {
+                    kafka.insert(property, Value::String(value));
+                }
+            }
+            Some((false, key)) => {
+                let value = match LIST_FIELDS.contains(&key.as_str()) {
+                    true => Value::Array(
+                        value
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|item| !item.is_empty())
+                            .map(|item| Value::String(item.to_string()))
+                            .collect(),
+                    ),
+                    false => Value::String(value),
+                };
+                table.insert(key, value);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Interpolates the `kafka` properties and the top-level `SECRET_KEYS`,
+/// then replaces each of them set as `<key>_file` with `<key>` set to the
+/// content of that file, relative to `home_dir`. Other values, e.g. regex
+/// patterns or filter expressions, are left as written.
+pub fn resolve_values(
+    config: &mut Value,
+    home_dir: &Path,
+    vars: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    let table = match config.as_table_mut() {
+        Some(table) => table,
+        None => return Ok(()),
+    };
+    if let Some(Value::Table(kafka)) = table.get_mut("kafka") {
+        resolve_table(kafka, home_dir, vars, |_| true)?;
+    }
+    resolve_table(table, home_dir, vars, |key| SECRET_KEYS.contains(&key))
+}
+
+fn resolve_table(
+    table: &mut toml::value::Table,
+    home_dir: &Path,
+    vars: &impl Fn(&str) -> Option<String>,
+    is_resolved: impl Fn(&str) -> bool,
+) -> anyhow::Result<()> {
+    for (key, value) in table.iter_mut() {
+        if let Value::String(value) = value {
+            if is_resolved(key.trim_end_matches(FILE_SUFFIX)) {
+                *value = interpolate(value, vars)?;
+            }
+        }
+    }
+
+    let file_keys: Vec<String> = table
+        .iter()
+        .filter(|(key, value)| {
+            key.ends_with(FILE_SUFFIX)
+                && is_resolved(key.trim_end_matches(FILE_SUFFIX))
+                && value.is_str()
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    for file_key in file_keys {
+        let path = table.remove(&file_key).unwrap();
+        let path = home_dir.join(path.as_str().unwrap());
+        let secret = std::fs::read_to_string(&path).map_err(|err| {
+            anyhow::anyhow!("Could not read {} from {:?}: {}", file_key, path, err)
+        })?;
+        let key = file_key.trim_end_matches(FILE_SUFFIX).to_string();
+        table.insert(key, Value::String(secret.trim_end().to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::test_utils::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn overrides() {
+        let vars: HashMap<&str, &str> = [("BROKER", "kafka:9092"), ("USER", "nes")].into();
+        let lookup = |name: &str| vars.get(name).map(|value| value.to_string());
+
+        assert_eq!(
+            interpolate("${BROKER}/${MISSING:-x}/$$HOME", &lookup).unwrap(),
+            "kafka:9092/x/$HOME"
+        );
+        assert!(interpolate("${MISSING}", &lookup).is_err());
+        assert!(interpolate("${BROKER", &lookup).is_err());
+
+        let home_dir = temp_dir("env");
+        std::fs::write(home_dir.join("nes-env-test.secret"), "s3cret\n").unwrap();
+
+        let mut config: Value = toml::from_str(
+            r#"
+stats_enabled=false
+whitelist_contract_ids=["a.near"]
+blacklist_contract_ids=["/^x\\$$/"]
+metadata_cache_path_file="nes-env-test.secret"
+
+[kafka]
+"bootstrap.servers"="${BROKER}"
+"sasl.username"="${USER}"
+"sasl.password_file"="nes-env-test.secret"
+"#,
+        )
+        .unwrap();
+        apply_env_overrides(
+            &mut config,
+            [
+                ("NES_STATS_ENABLED", "true"),
+                ("NES_TOPICS_PREFIX", "nes"),
+                ("NES_WHITELIST_CONTRACT_IDS", "a.near, *.paras.near"),
+                ("NES_FILTERS_BLACKLIST_SIGNER_IDS", "x.near,y.near"),
+                ("NES_SERVICE_HOST", "10.0.0.1"),
+                ("NES_PORT", "tcp://10.0.0.1:80"),
+                ("NES_ADMIN_TOKEN_FILE", "nes-env-test.secret"),
+                ("NES_KAFKA_SESSION_TIMEOUT_MS", "45000"),
+                ("NES_KAFKA_SSL__ENGINE", "x"),
+                ("OTHER", "y"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        resolve_values(&mut config, &home_dir, &lookup).unwrap();
+        std::fs::remove_dir_all(home_dir).unwrap();
+
+        assert_eq!(config["stats_enabled"].as_str(), Some("true"));
+        assert_eq!(config["near_events_topic_prefix"].as_str(), Some("nes"));
+        assert_eq!(
+            config["whitelist_contract_ids"][1].as_str(),
+            Some("*.paras.near")
+        );
+        let kafka = &config["kafka"];
+        assert_eq!(kafka["bootstrap.servers"].as_str(), Some("kafka:9092"));
+        assert_eq!(kafka["sasl.username"].as_str(), Some("nes"));
+        assert_eq!(kafka["sasl.password"].as_str(), Some("s3cret"));
+        assert!(kafka.get("sasl.password_file").is_none());
+        assert_eq!(kafka["session.timeout.ms"].as_str(), Some("45000"));
+        assert_eq!(kafka["ssl_engine"].as_str(), Some("x"));
+        assert!(config.get("other").is_none());
+        // Not in the file, still a list
+        assert_eq!(
+            config["blacklist_signer_ids"].as_array().map(Vec::len),
+            Some(2)
+        );
+        assert!(config.get("service_host").is_none());
+        assert!(config.get("port").is_none());
+        assert_eq!(config["admin_token"].as_str(), Some("s3cret"));
+        // Only the kafka properties and secrets are resolved
+        assert_eq!(
+            config["blacklist_contract_ids"][0].as_str(),
+            Some("/^x\\$$/")
+        );
+        assert!(config.get("metadata_cache_path").is_none());
+    }
+}
@@ -1,9 +1,15 @@
+use std::{convert::TryFrom, fmt};
+
+use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
-use crate::token::TokenMetadata;
+use crate::{
+    template::Template,
+    token::{EnrichmentStatus, MetadataSource, TokenMetadata},
+};
 
 lazy_static! {
     static ref REGEX_STANDARD: Regex = Regex::new(r"^[a-zA-Z0-9._-]+$").unwrap();
@@ -20,6 +26,11 @@ pub struct NearEvent {
     pub event: String,
     pub data: EventData,
     pub emit_info: Option<EmitInfo>,
+    /// Set by the enrichers on the events sent to the metadata topics,
+    /// keyed by enricher name. Never read from the event log, where any
+    /// contract could forge it.
+    #[serde(skip_deserializing, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub enrichment: serde_json::Map<String, serde_json::Value>,
 }
 
 impl NearEvent {
@@ -34,36 +45,203 @@ impl NearEvent {
         format!("{}.{}", self.standard, self.event)
     }
 
+    /// Kafka record key for the given strategy. Strategies that can't be
+    /// resolved for this event fall back to the contract key.
+    pub fn to_partition_key(&self, partition_key: &PartitionKey) -> String {
+        let key = match partition_key {
+            PartitionKey::Contract => None,
+            PartitionKey::ContractTokenId => self
+                .token_id()
+                .map(|token_id| format!("{}:{}", self.to_key(), token_id)),
+            PartitionKey::Owner => self.owner_id(),
+            PartitionKey::ReceiptId => self
+                .emit_info
+                .as_ref()
+                .map(|emit_info| emit_info.receipt_id.clone()),
+            PartitionKey::Template(template) => Some(template.render(self)),
+        };
+
+        key.unwrap_or_else(|| self.to_key())
+    }
+
+    /// First token id of the event data, from either `token_ids` or `token_id`.
+    pub fn token_id(&self) -> Option<String> {
+        self.data_items()
+            .first()
+            .and_then(|data| data.token_ids().into_iter().next())
+    }
+
+    /// Every token id listed in the event data.
+    pub fn token_ids(&self) -> Vec<String> {
+        self.data_items()
+            .iter()
+            .flat_map(DataItem::token_ids)
+            .collect()
+    }
+
+    /// Owner of the event data: `new_owner_id` for transfers, `owner_id` otherwise.
+    pub fn owner_id(&self) -> Option<String> {
+        let data = self.data_items().into_iter().next()?;
+        data.account_id("new_owner_id")
+            .or_else(|| data.account_id("owner_id"))
+            .map(|owner_id| owner_id.to_string())
+    }
+
+    /// Accounts named in the event data: `owner_id`, `old_owner_id`
+    /// and `new_owner_id`.
+    pub fn account_ids(&self) -> Vec<String> {
+        self.data_items()
+            .iter()
+            .flat_map(|data| {
+                ["owner_id", "old_owner_id", "new_owner_id"]
+                    .iter()
+                    .filter_map(|key| data.account_id(key))
+                    .map(|account_id| account_id.to_string())
+                    .collect::<Vec<String>>()
+            })
+            .unique()
+            .collect()
+    }
+
+    /// Items of the event data, read from the typed NEP-171 data as is.
+    fn data_items(&self) -> Vec<DataItem<'_>> {
+        match &self.data {
+            EventData::Nep171(Nep171Data::Mint(items)) => {
+                items.iter().map(DataItem::Mint).collect()
+            }
+            EventData::Nep171(Nep171Data::Transfer(items)) => {
+                items.iter().map(DataItem::Transfer).collect()
+            }
+            EventData::Nep171(Nep171Data::MintFlat(data)) => vec![DataItem::Mint(data)],
+            EventData::Nep171(Nep171Data::TransferFlat(data)) => vec![DataItem::Transfer(data)],
+            EventData::Generic(serde_json::Value::Array(items)) => {
+                items.iter().map(DataItem::Generic).collect()
+            }
+            EventData::Generic(data) => vec![DataItem::Generic(data)],
+        }
+    }
+
     pub fn to_topic(&self, prefix: &str) -> String {
         format!("{}.{}", prefix, &self.default_key())
     }
 
+    /// Deterministic id of the event: `receipt_id:log_index` for events
+    /// emitted by a contract and `receipt_id:log_index:flat_index` for
+    /// the flattened NEP-171 events.
+    pub fn to_event_id(&self) -> Option<String> {
+        self.emit_info
+            .as_ref()
+            .map(|emit_info| match emit_info.flat_index {
+                Some(flat_index) => format!(
+                    "{}:{}:{}",
+                    emit_info.receipt_id, emit_info.log_index, flat_index
+                ),
+                None => format!("{}:{}", emit_info.receipt_id, emit_info.log_index),
+            })
+    }
+
+    /// Splits a NEP-171 mint or transfer into one event per token, so each
+    /// flat event can be keyed and ordered by its token id. Burns have the
+    /// shape of mints and are left as they are.
     pub fn try_flatten_nep171_event(&self) -> Vec<NearEvent> {
-        match &self.data {
+        if !matches!(self.event.as_str(), "nft_mint" | "nft_transfer") {
+            return vec![];
+        }
+        let flat_events: Vec<NearEvent> = match &self.data {
             EventData::Nep171(data) => match data {
                 Nep171Data::Mint(data) => data
                     .iter()
-                    .map(|d| {
-                        let mut flat_event = self.clone();
-                        flat_event.data = EventData::Nep171(Nep171Data::MintFlat(d.clone()));
-                        flat_event
+                    .flat_map(|d| {
+                        d.token_ids.iter().map(|token_id| {
+                            let mut flat_event = self.clone();
+                            flat_event.data =
+                                EventData::Nep171(Nep171Data::MintFlat(Nep171MintData {
+                                    token_ids: vec![token_id.clone()],
+                                    ..d.clone()
+                                }));
+                            flat_event
+                        })
                     })
                     .collect(),
                 Nep171Data::Transfer(data) => data
                     .iter()
-                    .map(|d| {
-                        let mut flat_event = self.clone();
-                        flat_event.data = EventData::Nep171(Nep171Data::TransferFlat(d.clone()));
-                        flat_event
+                    .flat_map(|d| {
+                        d.token_ids.iter().map(|token_id| {
+                            let mut flat_event = self.clone();
+                            flat_event.data =
+                                EventData::Nep171(Nep171Data::TransferFlat(Nep171TransferData {
+                                    token_ids: vec![token_id.clone()],
+                                    ..d.clone()
+                                }));
+                            flat_event
+                        })
                     })
                     .collect(),
                 _ => vec![],
             },
             EventData::Generic(_) => vec![],
+        };
+
+        flat_events
+            .into_iter()
+            .enumerate()
+            .map(|(flat_index, mut flat_event)| {
+                if let Some(emit_info) = flat_event.emit_info.as_mut() {
+                    emit_info.flat_index = Some(flat_index);
+                }
+                flat_event
+            })
+            .collect()
+    }
+}
+
+/// How the Kafka record key is derived from an event. Configured as
+/// `contract`, `contract_token_id`, `owner`, `receipt_id` or a template
+/// such as `{contract}:{owner}`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PartitionKey {
+    #[default]
+    Contract,
+    ContractTokenId,
+    Owner,
+    ReceiptId,
+    Template(Template),
+}
+
+impl TryFrom<String> for PartitionKey {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "contract" => Ok(Self::Contract),
+            "contract_token_id" => Ok(Self::ContractTokenId),
+            "owner" => Ok(Self::Owner),
+            "receipt_id" => Ok(Self::ReceiptId),
+            _ if value.contains('{') => Ok(Self::Template(value.parse()?)),
+            _ => anyhow::bail!("Unknown partition key strategy `{}`", value),
+        }
+    }
+}
+
+impl fmt::Display for PartitionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Contract => f.write_str("contract"),
+            Self::ContractTokenId => f.write_str("contract_token_id"),
+            Self::Owner => f.write_str("owner"),
+            Self::ReceiptId => f.write_str("receipt_id"),
+            Self::Template(template) => template.fmt(f),
         }
     }
 }
 
+impl Serialize for PartitionKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "snake_case")]
 pub struct EmitInfo {
@@ -72,15 +250,62 @@ pub struct EmitInfo {
     pub block_height: u64,
     pub shard_id: u64,
     pub contract_account_id: String,
+    #[serde(default)]
+    pub log_index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flat_index: Option<usize>,
+    #[serde(default)]
+    pub predecessor_id: String,
+    /// Signer of the transaction, unknown for data receipts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum EventData {
     Nep171(Nep171Data),
     Generic(serde_json::Value),
 }
 
+/// One item of the event data, as seen by the accessors of [`NearEvent`].
+enum DataItem<'a> {
+    Mint(&'a Nep171MintData),
+    Transfer(&'a Nep171TransferData),
+    Generic(&'a serde_json::Value),
+}
+
+impl<'a> DataItem<'a> {
+    fn account_id(&self, key: &str) -> Option<&'a str> {
+        match (self, key) {
+            (Self::Mint(data), "owner_id") => Some(&data.owner_id),
+            (Self::Transfer(data), "old_owner_id") => Some(&data.old_owner_id),
+            (Self::Transfer(data), "new_owner_id") => Some(&data.new_owner_id),
+            (Self::Generic(data), key) => data.get(key).and_then(|value| value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Token ids from either `token_ids` or `token_id`.
+    fn token_ids(&self) -> Vec<String> {
+        let data = match self {
+            Self::Mint(data) => return data.token_ids.clone(),
+            Self::Transfer(data) => return data.token_ids.clone(),
+            Self::Generic(data) => data,
+        };
+        let token_ids = match data.get("token_ids").and_then(|ids| ids.as_array()) {
+            Some(token_ids) => token_ids.iter().collect(),
+            None => data.get("token_id").into_iter().collect::<Vec<_>>(),
+        };
+        token_ids
+            .into_iter()
+            .filter_map(|token_id| token_id.as_str())
+            .map(|token_id| token_id.to_string())
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Nep171Data {
@@ -101,6 +326,10 @@ pub struct Nep171MintData {
     pub metadata_extras: Option<Vec<Option<serde_json::Value>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub _ids: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_sources: Option<Vec<Option<MetadataSource>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_statuses: Option<Vec<EnrichmentStatus>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -116,6 +345,10 @@ pub struct Nep171TransferData {
     pub metadata_extras: Option<Vec<Option<serde_json::Value>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub _ids: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_sources: Option<Vec<Option<MetadataSource>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_statuses: Option<Vec<EnrichmentStatus>>,
 }
 
 #[cfg(test)]
@@ -128,5 +361,75 @@ mod tests {
         let event: NearEvent = serde_json::from_str(json).unwrap();
         let flat_events = event.try_flatten_nep171_event();
         println!("flatten events: {:?}", &flat_events);
+
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"old_owner_id":"a.testnet","new_owner_id":"b.testnet","token_ids":["1"]}],"enrichment":{"payout":{"payout":{"evil.testnet":"10000"}}}}"#;
+        let event: NearEvent = serde_json::from_str(json).unwrap();
+        assert!(event.enrichment.is_empty());
+    }
+
+    #[test]
+    fn event_ids() {
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"a.testnet","token_ids":["1"]},{"owner_id":"b.testnet","token_ids":["2", "3"]}]}"#;
+        let mut event: NearEvent = serde_json::from_str(json).unwrap();
+        assert_eq!(event.to_event_id(), None);
+
+        event.emit_info = Some(EmitInfo {
+            receipt_id: "receipt".to_string(),
+            log_index: 3,
+            ..Default::default()
+        });
+        assert_eq!(event.to_event_id(), Some("receipt:3".to_string()));
+
+        let flat_ids: Vec<Option<String>> = event
+            .try_flatten_nep171_event()
+            .iter()
+            .map(|e| e.to_event_id())
+            .collect();
+        assert_eq!(
+            flat_ids,
+            vec![
+                Some("receipt:3:0".to_string()),
+                Some("receipt:3:1".to_string()),
+                Some("receipt:3:2".to_string())
+            ]
+        );
+
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_burn","data":[{"owner_id":"a.testnet","token_ids":["1", "2"]}]}"#;
+        let burn: NearEvent = serde_json::from_str(json).unwrap();
+        assert!(burn.try_flatten_nep171_event().is_empty());
+    }
+
+    #[test]
+    fn partition_keys() {
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"old_owner_id":"a.testnet","new_owner_id":"b.testnet","token_ids":["1", "2"]}]}"#;
+        let mut event: NearEvent = serde_json::from_str(json).unwrap();
+        event.emit_info = Some(EmitInfo {
+            receipt_id: "receipt".to_string(),
+            contract_account_id: "nft.testnet".to_string(),
+            ..Default::default()
+        });
+
+        let keys: Vec<String> = event
+            .try_flatten_nep171_event()
+            .iter()
+            .map(|e| e.to_partition_key(&PartitionKey::ContractTokenId))
+            .collect();
+        assert_eq!(keys, vec!["nft.testnet:1", "nft.testnet:2"]);
+
+        assert_eq!(event.to_partition_key(&PartitionKey::Owner), "b.testnet");
+        assert_eq!(event.to_partition_key(&PartitionKey::ReceiptId), "receipt");
+        assert_eq!(
+            event.to_partition_key(&PartitionKey::try_from("{event}/{owner}".to_string()).unwrap()),
+            "nft_transfer/b.testnet"
+        );
+        assert_eq!(event.account_ids(), vec!["a.testnet", "b.testnet"]);
+        assert_eq!(event.token_ids(), vec!["1", "2"]);
+
+        // Data of other standards is read as JSON
+        let json = r#"{"standard":"game","version":"1.0.0","event":"level_up","data":{"owner_id":"c.testnet","token_id":"9"}}"#;
+        let generic: NearEvent = serde_json::from_str(json).unwrap();
+        assert_eq!(generic.token_id().as_deref(), Some("9"));
+        assert_eq!(generic.owner_id().as_deref(), Some("c.testnet"));
+        assert_eq!(generic.account_ids(), vec!["c.testnet"]);
     }
 }
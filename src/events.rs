@@ -1,110 +1,102 @@
 use std::time::Duration;
 
 use futures::{
-    stream::{self, FuturesOrdered, FuturesUnordered},
-    StreamExt, TryStreamExt,
+    stream::{FuturesOrdered, FuturesUnordered},
+    TryStreamExt,
 };
 use itertools::Itertools;
+use near_indexer::near_primitives::{
+    types::AccountId,
+    views::{ReceiptEnumView, ReceiptView},
+};
 use rdkafka::{
-    admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
-    client::DefaultClientContext,
-    consumer::{Consumer, StreamConsumer},
+    error::KafkaError,
+    message::OwnedHeaders,
     producer::{FutureProducer, FutureRecord},
+    types::RDKafkaErrorCode,
 };
 use tracing::{debug, info, warn};
 use validator::Validate;
 
 use crate::{
     configs::NesConfig,
-    event_types::{EmitInfo, EventData, NearEvent, Nep171Data},
-    token::get_metadatas,
+    enrichment::EnricherChain,
+    event_types::{EmitInfo, NearEvent, PartitionKey},
+    routing::route_event,
+    topics::TopicManager,
 };
 
-pub async fn ensure_topic(
-    consumer: &StreamConsumer,
-    admin_client: &AdminClient<DefaultClientContext>,
-    nes_config: &NesConfig,
-    topic: &str,
-) -> anyhow::Result<()> {
-    if !nes_config.force_create_new_topic {
-        return Ok(());
-    }
-    let metadata = consumer.fetch_metadata(None, Duration::from_secs(1));
-
-    if let Err(err) = &metadata {
-        warn!("Could not fetch Kafka metadata: {:?}", err);
-        return Ok(());
-    }
-
-    let metadata = metadata.unwrap();
-
-    let topic_names = metadata
-        .topics()
-        .iter()
-        .map(|t| t.name())
-        .collect::<Vec<&str>>();
-
-    debug!("Kafka topics: {:?}", topic_names);
-
-    let existed = metadata
-        .topics()
-        .iter()
-        .any(|topic_metadata| topic_metadata.name() == topic);
-
-    if !existed {
-        let results = admin_client
-            .create_topics(
-                &[NewTopic::new(
-                    topic,
-                    nes_config.new_topic_partitions,
-                    TopicReplication::Fixed(nes_config.new_topic_replication),
-                )],
-                &AdminOptions::new(),
-            )
-            .await?;
-
-        for result in results {
-            let status = result.map_err(|e| e.1);
-            let status = status?;
-            info!("Kafka created new topic: {:?}", status);
-        }
-    }
-
-    Ok(())
-}
-
 pub async fn send_event(
     producer: &FutureProducer,
-    consumer: &StreamConsumer,
-    admin_client: &AdminClient<DefaultClientContext>,
+    topics: &TopicManager,
     nes_config: &NesConfig,
     topic: &str,
+    partition_key: &PartitionKey,
     event: &NearEvent,
 ) -> anyhow::Result<()> {
-    ensure_topic(consumer, admin_client, nes_config, topic).await?;
-
     let payload = serde_json::to_string(event)?;
-    let key = event.to_key();
+    let key = event.to_partition_key(partition_key);
 
+    topics.ensure_topic(nes_config, topic).await?;
     let delivery_status = producer
         .send(
-            FutureRecord::to(topic).payload(&payload).key(&key),
+            FutureRecord::to(topic)
+                .payload(&payload)
+                .key(&key)
+                .headers(event_headers(event)),
             Duration::from_secs(0),
         )
         .await;
 
-    let delivery_status = delivery_status.map_err(|e| e.0);
+    let delivery_status = match delivery_status.map_err(|e| e.0) {
+        Err(KafkaError::MessageProduction(
+            RDKafkaErrorCode::UnknownTopicOrPartition | RDKafkaErrorCode::UnknownTopic,
+        )) if nes_config.force_create_new_topic => {
+            warn!(
+                "Kafka topic {} is unknown, will recreate it and retry",
+                topic
+            );
+            topics.forget(topic).await;
+            topics.ensure_topic(nes_config, topic).await?;
+            producer
+                .send(
+                    FutureRecord::to(topic)
+                        .payload(&payload)
+                        .key(&key)
+                        .headers(event_headers(event)),
+                    Duration::from_secs(0),
+                )
+                .await
+                .map_err(|e| e.0)
+        }
+        delivery_status => delivery_status,
+    };
     delivery_status?;
 
     Ok(())
 }
 
+fn event_headers(event: &NearEvent) -> OwnedHeaders {
+    let headers = OwnedHeaders::new()
+        .add("standard", &event.standard)
+        .add("version", &event.version)
+        .add("event", &event.event);
+
+    match (&event.emit_info, event.to_event_id()) {
+        (Some(emit_info), Some(event_id)) => headers
+            .add("block_height", &emit_info.block_height.to_string())
+            .add("shard_id", &emit_info.shard_id.to_string())
+            .add("receipt_id", &emit_info.receipt_id)
+            .add("event_id", &event_id),
+        _ => headers,
+    }
+}
+
 pub async fn store_events(
     streamer_message: &near_indexer::StreamerMessage,
     producer: &FutureProducer,
-    consumer: &StreamConsumer,
-    admin_client: &AdminClient<DefaultClientContext>,
-    view_client: &actix::Addr<near_client::ViewClientActor>,
+    topics: &TopicManager,
+    enrichers: &EnricherChain,
     nes_config: &NesConfig,
 ) -> anyhow::Result<()> {
     let block_height = streamer_message.block.header.height;
@@ -126,17 +118,7 @@ pub async fn store_events(
 
     event_partitions
         .values()
-        .into_iter()
-        .map(|events| {
-            send_events(
-                producer,
-                consumer,
-                admin_client,
-                nes_config,
-                view_client,
-                events,
-            )
-        })
+        .map(|events| send_events(producer, topics, nes_config, enrichers, events))
         .collect::<FuturesUnordered<_>>()
         .try_collect::<Vec<()>>()
         .await?;
@@ -146,40 +128,54 @@ pub async fn store_events(
 
 async fn send_events(
     producer: &FutureProducer,
-    consumer: &StreamConsumer,
-    admin_client: &AdminClient<DefaultClientContext>,
+    topics: &TopicManager,
     nes_config: &NesConfig,
-    view_client: &actix::Addr<near_client::ViewClientActor>,
+    enrichers: &EnricherChain,
     events: &[NearEvent],
 ) -> anyhow::Result<()> {
     for event in events.iter() {
-        let event_topic = event.to_topic(&nes_config.near_events_topic_prefix);
+        enrichers.token_client().invalidate(event);
 
-        let sending_to_all_topic = send_event(
-            producer,
-            consumer,
-            admin_client,
-            nes_config,
-            &nes_config.near_events_all_topic,
-            event,
-        );
+        let route = route_event(nes_config, event);
+        let metadata_topics = route.metadata_topics();
 
-        let sending_to_specific_topic = send_event(
-            producer,
-            consumer,
-            admin_client,
-            nes_config,
-            &event_topic,
-            event,
-        );
+        let sending_to_all_topic = async {
+            if !route.all_topic {
+                return Ok(());
+            }
+            send_event(
+                producer,
+                topics,
+                nes_config,
+                &nes_config.near_events_all_topic,
+                &nes_config.all_topic_partition_key,
+                event,
+            )
+            .await
+        };
+
+        let sending_to_specific_topic = route
+            .topics
+            .iter()
+            .map(|topic| {
+                send_event(
+                    producer,
+                    topics,
+                    nes_config,
+                    topic,
+                    &route.partition_key,
+                    event,
+                )
+            })
+            .collect::<FuturesUnordered<_>>()
+            .try_collect::<Vec<()>>();
 
         let sending_event_with_metadata = send_event_with_metadata(
             producer,
-            consumer,
-            admin_client,
+            topics,
             nes_config,
-            view_client,
-            &event_topic,
+            enrichers,
+            &metadata_topics,
             event,
         );
 
@@ -197,34 +193,31 @@ async fn send_events(
 
 async fn send_event_with_metadata(
     producer: &FutureProducer,
-    consumer: &StreamConsumer,
-    admin_client: &AdminClient<DefaultClientContext>,
+    topics: &TopicManager,
     nes_config: &NesConfig,
-    view_client: &actix::Addr<near_client::ViewClientActor>,
-    event_topic: &str,
+    enrichers: &EnricherChain,
+    metadata_topics: &[String],
     event: &NearEvent,
 ) -> anyhow::Result<()> {
-    if !nes_config.enrich_metadata {
+    if !nes_config.enrich_metadata || metadata_topics.is_empty() {
         return Ok(());
     }
 
-    let contract_account_id = event.emit_info.clone().map(|info| info.contract_account_id);
-    if contract_account_id.is_none() {
-        return Ok(());
-    }
-    let contract_account_id = contract_account_id.unwrap();
-    let topic = format!("{}_metadata", event_topic);
-
-    let events = stream::iter(event.try_flatten_nep171_event());
-
-    let enriched_events = events
-        .then(|event| enrich_event_metadata(view_client, event, &contract_account_id))
-        .try_collect::<Vec<NearEvent>>()
-        .await?;
+    let enriched_events = enrichers.enrich(nes_config, event).await?;
 
     enriched_events
         .iter()
-        .map(|event| send_event(producer, consumer, admin_client, nes_config, &topic, event))
+        .flat_map(|event| metadata_topics.iter().map(move |topic| (topic, event)))
+        .map(|(topic, event)| {
+            send_event(
+                producer,
+                topics,
+                nes_config,
+                topic,
+                &nes_config.metadata_topic_partition_key,
+                event,
+            )
+        })
         .collect::<FuturesOrdered<_>>()
         .try_collect::<Vec<()>>()
         .await?;
@@ -232,37 +225,6 @@ async fn send_event_with_metadata(
     Ok(())
 }
 
-async fn enrich_event_metadata(
-    view_client: &actix::Addr<near_client::ViewClientActor>,
-    event: NearEvent,
-    contract_account_id: &str,
-) -> anyhow::Result<NearEvent> {
-    let mut enriched_data = event.data.clone();
-    match enriched_data {
-        EventData::Nep171(Nep171Data::MintFlat(ref mut data)) => {
-            let (_ids, metadatas, extras) =
-                get_metadatas(view_client, contract_account_id, &data.token_ids).await?;
-
-            data._ids = Some(_ids);
-            data.metadatas = Some(metadatas);
-            data.metadata_extras = Some(extras);
-        }
-        EventData::Nep171(Nep171Data::TransferFlat(ref mut data)) => {
-            let (_ids, metadatas, extras) =
-                get_metadatas(view_client, contract_account_id, &data.token_ids).await?;
-
-            data._ids = Some(_ids);
-            data.metadatas = Some(metadatas);
-            data.metadata_extras = Some(extras);
-        }
-        _ => {}
-    }
-
-    let mut enriched_event = event.clone();
-    enriched_event.data = enriched_data;
-    Ok(enriched_event)
-}
-
 fn collect_events(
     shard: &near_indexer::IndexerShard,
     block_height: u64,
@@ -272,28 +234,25 @@ fn collect_events(
     shard
         .receipt_execution_outcomes
         .iter()
-        .flat_map(|outcome| extract_events(outcome, block_height, block_timestamp, shard.shard_id))
-        .filter(|e| {
-            if nes_config.whitelist_contract_ids.is_empty() {
-                return true;
-            }
-            let emit_info = e.clone().emit_info.unwrap_or_default();
-            nes_config
-                .whitelist_contract_ids
-                .contains(&emit_info.contract_account_id)
-        })
-        .filter(|e| {
-            if nes_config.blacklist_contract_ids.is_empty() {
-                return true;
-            }
-            let emit_info = e.clone().emit_info.unwrap_or_default();
-            !nes_config
-                .blacklist_contract_ids
-                .contains(&emit_info.contract_account_id)
+        .filter(|outcome| {
+            nes_config.is_contract_allowed(outcome.receipt.receiver_id.as_ref())
+                && nes_config.is_receipt_allowed(
+                    outcome.receipt.predecessor_id.as_ref(),
+                    signer_id(&outcome.receipt).map(|signer_id| signer_id.as_ref()),
+                )
         })
+        .flat_map(|outcome| extract_events(outcome, block_height, block_timestamp, shard.shard_id))
+        .filter(|event| nes_config.is_event_allowed(event))
         .collect::<Vec<NearEvent>>()
 }
 
+fn signer_id(receipt: &ReceiptView) -> Option<&AccountId> {
+    match &receipt.receipt {
+        ReceiptEnumView::Action { signer_id, .. } => Some(signer_id),
+        ReceiptEnumView::Data { .. } => None,
+    }
+}
+
 fn extract_events(
     outcome: &near_indexer::IndexerExecutionOutcomeWithReceipt,
     block_height: u64,
@@ -307,9 +266,13 @@ fn extract_events(
         shard_id,
         receipt_id: outcome.receipt.receipt_id.to_string(),
         contract_account_id: outcome.receipt.receiver_id.to_string(),
+        predecessor_id: outcome.receipt.predecessor_id.to_string(),
+        signer_id: signer_id(&outcome.receipt).map(|signer_id| signer_id.to_string()),
+        log_index: 0,
+        flat_index: None,
     };
 
-    outcome.execution_outcome.outcome.logs.iter().filter_map(|untrimmed_log| {
+    outcome.execution_outcome.outcome.logs.iter().enumerate().filter_map(|(log_index, untrimmed_log)| {
         let log = untrimmed_log.trim();
         if !log.starts_with(prefix) {
             return None;
@@ -321,7 +284,7 @@ fn extract_events(
             Ok(event) => {
                 let result = event.validate();
                 match result {
-                    Ok(_) => Some(event),
+                    Ok(_) => Some((log_index, event)),
                     Err(err) => {
                         warn!(
                             target: crate::INDEXER,
@@ -343,8 +306,11 @@ fn extract_events(
                 None
             }
         }
-    }).map(|mut e| {
-        e.emit_info = Some(emit_info.clone());
+    }).map(|(log_index, mut e)| {
+        e.emit_info = Some(EmitInfo {
+            log_index,
+            ..emit_info.clone()
+        });
         e
     }).collect()
 }
@@ -0,0 +1,502 @@
+use std::{cmp::Ordering, convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::event_types::NearEvent;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    /// Source of a number, integers are kept exact
+    Num(String),
+    Dot,
+    Star,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Not,
+    And,
+    Or,
+    Op(Op),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    All,
+}
+
+/// A path into a JSON value, e.g. `data[*].token_ids[0]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(Vec<Step>);
+
+impl Path {
+    /// The values at the path, several ones with `[*]`.
+    pub fn values<'a>(&self, context: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![context];
+        for step in &self.0 {
+            current = current
+                .into_iter()
+                .flat_map(|value| match (step, value) {
+                    (Step::Key(key), Value::Object(fields)) => {
+                        fields.get(key).into_iter().collect()
+                    }
+                    (Step::Index(index), Value::Array(items)) => {
+                        items.get(*index).into_iter().collect()
+                    }
+                    (Step::All, Value::Array(items)) => items.iter().collect(),
+                    _ => vec![],
+                })
+                .collect();
+        }
+        current
+    }
+}
+
+impl FromStr for Path {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let node = match parser.next() {
+            Some(Token::Ident(first)) => parser.path(first)?,
+            token => anyhow::bail!("Invalid path `{}`: unexpected {:?}", s, token),
+        };
+        match (node, parser.peek()) {
+            (Node::Path(path), None) => Ok(path),
+            (_, token) => anyhow::bail!("Invalid path `{}`: unexpected {:?}", s, token),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Path(Path),
+    Literal(Value),
+    Int(i128),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(Box<Node>, Op, Box<Node>),
+}
+
+/// A filter expression over an event, e.g.
+/// `data[*].new_owner_id == "market.near" && block_height > 90000000`.
+///
+/// Paths start from the event (`standard`, `version`, `event`, `data`) and
+/// the fields of its `emit_info` (`block_height`, `contract_account_id`, ...).
+/// A path with `[*]` yields every item, and a comparison is true when any
+/// of the values it yields matches. Operators are `== != > >= < <=`,
+/// `&&`, `||`, `!` and parentheses.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// The event as expressions see it, built once for all the
+    /// expressions evaluated on an event.
+    pub fn context(event: &NearEvent) -> Value {
+        let mut context = serde_json::to_value(event).unwrap_or(Value::Null);
+        if let Value::Object(fields) = &mut context {
+            if let Some(Value::Object(emit_info)) = fields.get("emit_info").cloned() {
+                for (key, value) in emit_info {
+                    fields.entry(key).or_insert(value);
+                }
+            }
+        }
+
+        context
+    }
+
+    pub fn is_match(&self, context: &Value) -> bool {
+        eval(&self.root, context)
+    }
+}
+
+/// A value yielded by an operand, borrowed from the context or the expression.
+#[derive(Debug, Clone, Copy)]
+enum Operand<'a> {
+    Value(&'a Value),
+    Int(i128),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn as_f64(self) -> f64 {
+        match self {
+            Self::Int(n) => n as f64,
+            Self::Float(n) => n,
+        }
+    }
+}
+
+fn eval(node: &Node, context: &Value) -> bool {
+    match node {
+        Node::Not(node) => !eval(node, context),
+        Node::And(left, right) => eval(left, context) && eval(right, context),
+        Node::Or(left, right) => eval(left, context) || eval(right, context),
+        Node::Compare(left, op, right) => {
+            let left = values(left, context);
+            let right = values(right, context);
+            left.iter()
+                .any(|l| right.iter().any(|r| compare(l, *op, r)))
+        }
+        node => values(node, context).iter().any(is_truthy),
+    }
+}
+
+fn values<'a>(node: &'a Node, context: &'a Value) -> Vec<Operand<'a>> {
+    match node {
+        Node::Path(path) => path
+            .values(context)
+            .into_iter()
+            .map(Operand::Value)
+            .collect(),
+        Node::Literal(value) => vec![Operand::Value(value)],
+        Node::Int(n) => vec![Operand::Int(*n)],
+        node => vec![Operand::Bool(eval(node, context))],
+    }
+}
+
+fn is_truthy(operand: &Operand) -> bool {
+    match operand {
+        Operand::Value(value) => !matches!(value, Value::Null | Value::Bool(false)),
+        Operand::Int(_) => true,
+        Operand::Bool(b) => *b,
+    }
+}
+
+/// Numbers are often sent as strings, e.g. balances. Integers are compared
+/// exactly, yoctoNEAR amounts don't fit in a f64.
+fn number(operand: &Operand) -> Option<Number> {
+    match operand {
+        Operand::Int(n) => Some(Number::Int(*n)),
+        Operand::Value(Value::Number(n)) => n
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| n.as_u64().map(i128::from))
+            .map(Number::Int)
+            .or_else(|| n.as_f64().map(Number::Float)),
+        Operand::Value(Value::String(s)) => s
+            .parse::<i128>()
+            .map(Number::Int)
+            .or_else(|_| s.parse::<f64>().map(Number::Float))
+            .ok(),
+        _ => None,
+    }
+}
+
+fn compare(left: &Operand, op: Op, right: &Operand) -> bool {
+    let ordering = match (left, right) {
+        (Operand::Value(Value::String(l)), Operand::Value(Value::String(r))) => Some(l.cmp(r)),
+        (Operand::Bool(l), Operand::Bool(r))
+        | (Operand::Bool(l), Operand::Value(Value::Bool(r)))
+        | (Operand::Value(Value::Bool(l)), Operand::Bool(r)) => (l == r).then_some(Ordering::Equal),
+        (l, r) => match (number(l), number(r)) {
+            (Some(Number::Int(l)), Some(Number::Int(r))) => Some(l.cmp(&r)),
+            (Some(l), Some(r)) => l.as_f64().partial_cmp(&r.as_f64()),
+            _ => match (l, r) {
+                (Operand::Value(l), Operand::Value(r)) if l == r => Some(Ordering::Equal),
+                _ => None,
+            },
+        },
+    };
+
+    match (op, ordering) {
+        (Op::Eq, Some(ordering)) => ordering == Ordering::Equal,
+        (Op::Ne, ordering) => ordering != Some(Ordering::Equal),
+        (Op::Gt, Some(ordering)) => ordering == Ordering::Greater,
+        (Op::Ge, Some(ordering)) => ordering != Ordering::Less,
+        (Op::Lt, Some(ordering)) => ordering == Ordering::Less,
+        (Op::Le, Some(ordering)) => ordering != Ordering::Greater,
+        _ => false,
+    }
+}
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('.', _) => (Token::Dot, 1),
+            ('*', _) => (Token::Star, 1),
+            ('[', _) => (Token::LBracket, 1),
+            (']', _) => (Token::RBracket, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('"', _) | ('\'', _) => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&ch| ch == c)
+                    .ok_or_else(|| anyhow::anyhow!("Unclosed string in `{}`", s))?;
+                let literal: String = chars[i + 1..i + 1 + end].iter().collect();
+                (Token::Str(literal), end + 2)
+            }
+            (c, _) if c.is_ascii_digit() || c == '-' => {
+                let len = chars[i + 1..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_digit() || **ch == '.')
+                    .count()
+                    + 1;
+                let literal: String = chars[i..i + len].iter().collect();
+                literal.parse::<f64>()?;
+                (Token::Num(literal), len)
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|ch| ch.is_alphanumeric() || **ch == '_')
+                    .count();
+                (Token::Ident(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => anyhow::bail!("Unexpected `{}` in `{}`", c, s),
+        };
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> anyhow::Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            token => anyhow::bail!("Expected {:?}, found {:?}", expected, token),
+        }
+    }
+
+    fn or(&mut self) -> anyhow::Result<Node> {
+        let mut node = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Node> {
+        let mut node = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Node> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.compare()
+    }
+
+    fn compare(&mut self) -> anyhow::Result<Node> {
+        let left = self.operand()?;
+        if let Some(Token::Op(op)) = self.peek().cloned() {
+            self.next();
+            let right = self.operand()?;
+            return Ok(Node::Compare(Box::new(left), op, Box::new(right)));
+        }
+        Ok(left)
+    }
+
+    fn operand(&mut self) -> anyhow::Result<Node> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let node = self.or()?;
+                self.expect(Token::RParen)?;
+                Ok(node)
+            }
+            Some(Token::Str(s)) => Ok(Node::Literal(Value::String(s))),
+            Some(Token::Num(n)) => Ok(match n.parse::<i128>() {
+                Ok(n) => Node::Int(n),
+                Err(_) => Node::Literal(
+                    serde_json::Number::from_f64(n.parse()?)
+                        .map(Value::Number)
+                        .unwrap_or(Value::Null),
+                ),
+            }),
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                _ => self.path(ident),
+            },
+            token => anyhow::bail!("Unexpected {:?}", token),
+        }
+    }
+
+    fn path(&mut self, first: String) -> anyhow::Result<Node> {
+        let mut steps = vec![Step::Key(first)];
+        loop {
+            match self.peek() {
+                Some(Token::Dot) => {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Ident(key)) => steps.push(Step::Key(key)),
+                        token => anyhow::bail!("Expected a field name, found {:?}", token),
+                    }
+                }
+                Some(Token::LBracket) => {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Star) => steps.push(Step::All),
+                        Some(Token::Num(n)) if n.parse::<usize>().is_ok() => {
+                            steps.push(Step::Index(n.parse()?))
+                        }
+                        Some(Token::Str(key)) => steps.push(Step::Key(key)),
+                        token => anyhow::bail!("Invalid index {:?}", token),
+                    }
+                    self.expect(Token::RBracket)?;
+                }
+                _ => return Ok(Node::Path(Path(steps))),
+            }
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let root = parser
+            .or()
+            .map_err(|err| anyhow::anyhow!("Invalid expression `{}`: {}", s, err))?;
+        if let Some(token) = parser.peek() {
+            anyhow::bail!("Invalid expression `{}`: unexpected {:?}", s, token);
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Expr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_types::EmitInfo;
+
+    #[test]
+    fn expressions() {
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"old_owner_id":"a.near","new_owner_id":"market.near","token_ids":["7"]}]}"#;
+        let mut event: NearEvent = serde_json::from_str(json).unwrap();
+        event.emit_info = Some(EmitInfo {
+            contract_account_id: "nft.near".to_string(),
+            block_height: 90000001,
+            ..Default::default()
+        });
+
+        let context = Expr::context(&event);
+        let is_match = |s: &str| s.parse::<Expr>().unwrap().is_match(&context);
+        assert!(is_match(r#"data[*].new_owner_id == "market.near""#));
+        assert!(is_match("block_height > 90000000"));
+        assert!(is_match(
+            r#"event == 'nft_transfer' && (data[0].token_ids[*] == "7" || false)"#
+        ));
+        assert!(is_match("emit_info.contract_account_id != \"x.near\""));
+        assert!(is_match("data[0].memo == null || !data[0].memo"));
+        assert!(!is_match(r#"data[*].old_owner_id == "market.near""#));
+        assert!(!is_match("block_height <= 90000000"));
+        assert!(!is_match("data[*].authorized_id"));
+
+        // Integers past 2^53 are compared exactly, also when sent as strings
+        let context = serde_json::json!({"balance": "1000000000000000000000001", "nonce": 9007199254740993u64});
+        let is_match = |s: &str| s.parse::<Expr>().unwrap().is_match(&context);
+        assert!(is_match("balance > 1000000000000000000000000"));
+        assert!(!is_match("balance == 1000000000000000000000000"));
+        assert!(is_match("nonce != 9007199254740992"));
+        assert!(is_match("nonce == '9007199254740993'"));
+        assert!(is_match("balance > 1.5"));
+
+        assert!("block_height >".parse::<Expr>().is_err());
+        assert!("(a == 1".parse::<Expr>().is_err());
+        assert!("a == 'b".parse::<Expr>().is_err());
+        assert!("a b".parse::<Expr>().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+use crate::{event_types::NearEvent, matcher::Pattern};
+
+/// A semver requirement on the event version, e.g. `>=1.0.0, <2`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct VersionRange(VersionReq);
+
+impl VersionRange {
+    /// Versions that aren't valid semver never match.
+    pub fn is_match(&self, version: &str) -> bool {
+        Version::parse(version)
+            .map(|version| self.0.matches(&version))
+            .unwrap_or(false)
+    }
+}
+
+impl FromStr for VersionRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(VersionReq::parse(s)?))
+    }
+}
+
+impl TryFrom<String> for VersionRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An `[[include_events]]`/`[[exclude_events]]` rule of `nes.toml`.
+/// Unset fields match any event.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventFilter {
+    pub standard: Option<Pattern>,
+    pub event: Option<Pattern>,
+    pub version: Option<VersionRange>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &NearEvent) -> bool {
+        self.standard
+            .as_ref()
+            .map(|standard| standard.is_match(&event.standard))
+            .unwrap_or(true)
+            && self
+                .event
+                .as_ref()
+                .map(|name| name.is_match(&event.event))
+                .unwrap_or(true)
+            && self
+                .version
+                .as_ref()
+                .map(|version| version.is_match(&event.version))
+                .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_filters() {
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[]}"#;
+        let event: NearEvent = serde_json::from_str(json).unwrap();
+
+        let filter = EventFilter {
+            standard: Some("nep171".parse().unwrap()),
+            event: Some("nft_*".parse().unwrap()),
+            version: Some(">=1.0.0".parse().unwrap()),
+        };
+        assert!(filter.matches(&event));
+
+        let filter = EventFilter {
+            version: Some(">=1.1.0".parse().unwrap()),
+            ..filter
+        };
+        assert!(!filter.matches(&event));
+
+        let range: VersionRange = "^1".parse().unwrap();
+        assert!(range.is_match("1.2.3"));
+        assert!(!range.is_match("2.0.0"));
+        assert!(!range.is_match("1.0"));
+    }
+}
@@ -1,24 +1,42 @@
-use std::sync::Arc;
+use std::{rc::Rc, sync::Arc, time::Duration};
 
+use admin::serve_admin;
+use arc_swap::ArcSwap;
+use cache::persist_periodically;
 use clap::Parser;
 use configs::{NesConfig, Opts, SubCommand};
-use events::store_events;
 use futures::StreamExt;
 use near_indexer::{get_default_home, indexer_init_configs, Indexer};
 use openssl_probe::init_ssl_cert_env_vars;
-use rdkafka::{
-    admin::AdminClient, client::DefaultClientContext, consumer::StreamConsumer,
-    producer::FutureProducer,
-};
-use stats::{end_process_block, start_process_block, stats_logger, Stats};
+use pipeline::{Checkpoint, Pipeline};
+use reload::watch_config;
+use stats::{stats_logger, Stats};
+use token::{ContractMetadataCache, TokenCache, TokenClient};
 use tokio::sync::Mutex;
 use tracing_subscriber::EnvFilter;
 
+mod admin;
+mod cache;
+mod check;
 mod configs;
+mod enrichment;
+mod env;
 mod event_types;
 mod events;
+mod expr;
+mod filter;
+mod matcher;
+mod pipeline;
+mod reload;
+mod resolver;
+mod routing;
+mod sections;
 mod stats;
+mod template;
+#[cfg(test)]
+mod test_utils;
 mod token;
+mod topics;
 
 pub const INDEXER: &str = "near_event_streams";
 
@@ -35,8 +53,16 @@ fn main() -> anyhow::Result<()> {
 
     match opts.subcmd {
         SubCommand::Run(args) => {
-            let indexer_config = args.to_indexer_config(home_dir.clone());
-            let nes_config = NesConfig::new(home_dir)?;
+            let configs: Vec<Arc<ArcSwap<NesConfig>>> = NesConfig::pipelines(home_dir.clone())?
+                .into_iter()
+                .map(|nes_config| Arc::new(ArcSwap::from_pointee(nes_config)))
+                .collect();
+            let checkpoints = configs
+                .iter()
+                .map(|config| Checkpoint::load(&config.load().checkpoint_path))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let indexer_config =
+                args.to_indexer_config(home_dir, Checkpoint::resume_height(&checkpoints));
 
             let system = actix::System::new();
             system.block_on(async move {
@@ -44,17 +70,30 @@ fn main() -> anyhow::Result<()> {
                 let stream = indexer.streamer();
                 let view_client = indexer.client_actors().0;
 
-                let stats: Arc<Mutex<Stats>> = Arc::new(Mutex::new(Stats::new()));
-                if nes_config.stats_enabled {
-                    actix::spawn(stats_logger(Arc::clone(&stats), view_client.clone()));
-                }
+                let stats: Vec<Arc<Mutex<Stats>>> = configs
+                    .iter()
+                    .map(|_| Arc::new(Mutex::new(Stats::new())))
+                    .collect();
+                configs.iter().zip(&stats).for_each(|(config, stats)| {
+                    actix::spawn(stats_logger(
+                        Arc::clone(stats),
+                        view_client.clone(),
+                        Arc::clone(config),
+                    ));
+                });
+                let admin_configs = configs.clone();
+                actix::spawn(async move {
+                    if let Err(err) = serve_admin(admin_configs).await {
+                        tracing::error!("Admin API stopped: {:?}", err);
+                    }
+                });
 
                 listen_blocks(
                     stream,
                     args.concurrency,
-                    nes_config,
+                    configs,
                     view_client.clone(),
-                    stats.clone(),
+                    stats,
                 )
                 .await
                 .expect("Exitting...");
@@ -64,6 +103,7 @@ fn main() -> anyhow::Result<()> {
             system.run()?;
         }
         SubCommand::Init(config) => indexer_init_configs(&home_dir, config.into())?,
+        SubCommand::CheckConfig => check::check_config(home_dir)?,
     }
 
     Ok(())
@@ -111,26 +151,50 @@ fn init_tracer(opts: &Opts) {
 async fn listen_blocks(
     stream: tokio::sync::mpsc::Receiver<near_indexer::StreamerMessage>,
     concurrency: std::num::NonZeroU16,
-    nes_config: NesConfig,
+    configs: Vec<Arc<ArcSwap<NesConfig>>>,
     view_client: actix::Addr<near_client::ViewClientActor>,
-    stats: Arc<Mutex<Stats>>,
+    stats: Vec<Arc<Mutex<Stats>>>,
 ) -> anyhow::Result<()> {
-    let producer: FutureProducer = nes_config.kafka_config.create()?;
-    let consumer: StreamConsumer = nes_config.kafka_config.create()?;
-    let admin_client: AdminClient<DefaultClientContext> = nes_config.kafka_config.create()?;
+    // The metadata caches are shared by the pipelines, their keys can only
+    // be set outside of [pipelines]
+    let nes_config = configs[0].load_full();
+
+    let token_cache = Arc::new(TokenCache::new(
+        nes_config.metadata_cache_capacity,
+        Duration::from_secs(nes_config.metadata_cache_ttl_secs),
+    ));
+    if let Some(path) = &nes_config.metadata_cache_path {
+        // Only a cache, one saved in an older format is started over
+        if let Err(err) = token_cache.load(path) {
+            tracing::warn!("Could not load the metadata cache {:?}: {:?}", path, err);
+        }
+        actix::spawn(persist_periodically(
+            Arc::clone(&token_cache),
+            path.clone(),
+            Duration::from_secs(nes_config.metadata_cache_persist_secs),
+        ));
+    }
+    let contract_cache = ContractMetadataCache::new(
+        nes_config.contract_metadata_cache_capacity,
+        Duration::from_secs(nes_config.metadata_cache_ttl_secs),
+    );
+    let token_client = Arc::new(TokenClient::new(view_client, token_cache, contract_cache));
+    let pipelines = configs
+        .into_iter()
+        .zip(stats)
+        .map(|(config, stats)| Pipeline::new(config, stats, Arc::clone(&token_client)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let pipelines = Rc::new(pipelines);
+
+    let reload_pipelines = Rc::clone(&pipelines);
+    actix::spawn(async move {
+        if let Err(err) = watch_config(reload_pipelines, token_client).await {
+            tracing::error!("Config reloading stopped: {:?}", err);
+        }
+    });
 
     let mut handle_messages = tokio_stream::wrappers::ReceiverStream::new(stream)
-        .map(|streamer_message| {
-            handle_message(
-                streamer_message,
-                &producer,
-                &consumer,
-                &admin_client,
-                &view_client,
-                &nes_config,
-                stats.clone(),
-            )
-        })
+        .map(|streamer_message| handle_message(streamer_message, &pipelines))
         .buffer_unordered(usize::from(concurrency.get()));
 
     while let Some(handle_message) = handle_messages.next().await {
@@ -140,29 +204,29 @@ async fn listen_blocks(
     Ok(())
 }
 
+/// Feeds the block to every pipeline, concurrently. A pipeline failing is
+/// stopped without affecting the others, the indexer only stops once all
+/// of them failed.
 async fn handle_message(
     streamer_message: near_indexer::StreamerMessage,
-    producer: &FutureProducer,
-    consumer: &StreamConsumer,
-    admin_client: &AdminClient<DefaultClientContext>,
-    view_client: &actix::Addr<near_client::ViewClientActor>,
-    nes_config: &NesConfig,
-    stats: Arc<Mutex<Stats>>,
+    pipelines: &[Pipeline],
 ) -> anyhow::Result<()> {
     let block_height = streamer_message.block.header.height;
-    start_process_block(&stats, block_height).await;
-
-    store_events(
-        &streamer_message,
-        producer,
-        consumer,
-        admin_client,
-        view_client,
-        nes_config,
+    let results = futures::future::join_all(
+        pipelines
+            .iter()
+            .map(|pipeline| pipeline.handle_message(&streamer_message)),
     )
-    .await?;
+    .await;
 
-    end_process_block(&stats, block_height).await;
+    for (pipeline, result) in pipelines.iter().zip(results) {
+        if let Err(err) = result {
+            pipeline.fail(block_height, err);
+        }
+    }
+    if pipelines.iter().all(Pipeline::has_failed) {
+        anyhow::bail!("Every pipeline stopped");
+    }
 
     Ok(())
 }
@@ -0,0 +1,197 @@
+use std::{collections::HashSet, convert::TryFrom, fmt, str::FromStr};
+
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+
+/// A string matcher configured in `nes.toml`. Values starting with `^` are
+/// regexes (`^nft-.*\.mintbase1\.near$`), values containing `*` or `?` are
+/// globs (`*.paras.near`) and anything else matches exactly.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Pattern {
+    Exact(String),
+    Glob(String, Regex),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact == value,
+            Self::Glob(_, regex) | Self::Regex(regex) => regex.is_match(value),
+        }
+    }
+
+    fn regex_source(&self) -> Option<&str> {
+        match self {
+            Self::Exact(_) => None,
+            Self::Glob(_, regex) | Self::Regex(regex) => Some(regex.as_str()),
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut source = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => source.push_str(".*"),
+            '?' => source.push('.'),
+            _ => source.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    source.push('$');
+    Regex::new(&source)
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('^') {
+            return Ok(Self::Regex(Regex::new(s)?));
+        }
+        if s.contains(['*', '?']) {
+            return Ok(Self::Glob(s.to_string(), glob_to_regex(s)?));
+        }
+        Ok(Self::Exact(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Patterns are equal when written the same way.
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exact(source) | Self::Glob(source, _) => f.write_str(source),
+            Self::Regex(regex) => f.write_str(regex.as_str()),
+        }
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A list of patterns compiled together, so matching costs one hash lookup
+/// for the exact values and one pass of a [`RegexSet`] for the others.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct PatternSet {
+    sources: Vec<String>,
+    exact: HashSet<String>,
+    regexes: Option<RegexSet>,
+}
+
+impl PatternSet {
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.regexes.is_none()
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.exact.contains(value)
+            || self
+                .regexes
+                .as_ref()
+                .map(|regexes| regexes.is_match(value))
+                .unwrap_or(false)
+    }
+}
+
+impl From<PatternSet> for Vec<String> {
+    fn from(set: PatternSet) -> Self {
+        set.sources
+    }
+}
+
+impl TryFrom<Vec<String>> for PatternSet {
+    type Error = anyhow::Error;
+
+    fn try_from(values: Vec<String>) -> Result<Self, Self::Error> {
+        let patterns = values
+            .iter()
+            .map(|value| value.parse())
+            .collect::<anyhow::Result<Vec<Pattern>>>()?;
+
+        let exact = patterns
+            .iter()
+            .filter_map(|pattern| match pattern {
+                Pattern::Exact(exact) => Some(exact.clone()),
+                _ => None,
+            })
+            .collect();
+        let sources: Vec<&str> = patterns.iter().filter_map(Pattern::regex_source).collect();
+        let regexes = match sources.is_empty() {
+            true => None,
+            false => Some(RegexSet::new(sources)?),
+        };
+
+        Ok(Self {
+            sources: values,
+            exact,
+            regexes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let pattern: Pattern = "*.paras.near".parse().unwrap();
+        assert!(pattern.is_match("x.paras.near"));
+        assert!(!pattern.is_match("paras.near"));
+        assert!(!pattern.is_match("x.paras.nearx"));
+        assert_eq!(pattern, "*.paras.near".parse().unwrap());
+        assert_ne!(pattern, "*.paras.testnet".parse().unwrap());
+
+        let pattern: Pattern = "nft-?.near".parse().unwrap();
+        assert!(pattern.is_match("nft-1.near"));
+        assert!(!pattern.is_match("nft-10.near"));
+
+        let pattern: Pattern = "a.near".parse().unwrap();
+        assert!(pattern.is_match("a.near"));
+        assert!(!pattern.is_match("aanear"));
+
+        let pattern: Pattern = r"^nft-.*\.mintbase1\.near$".parse().unwrap();
+        assert!(pattern.is_match("nft-x.mintbase1.near"));
+        assert!(!pattern.is_match("x.mintbase1.near"));
+        assert!("^(".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn pattern_sets() {
+        let set = PatternSet::try_from(vec![
+            "a.near".to_string(),
+            "*.paras.near".to_string(),
+            r"^nft-\d+\.near$".to_string(),
+        ])
+        .unwrap();
+        assert!(set.is_match("a.near"));
+        assert!(set.is_match("x.paras.near"));
+        assert!(set.is_match("nft-1.near"));
+        assert!(!set.is_match("b.near"));
+
+        assert!(PatternSet::try_from(vec![]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    cell::{Cell, RefCell},
+    path::Path,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    configs::NesConfig,
+    events::store_events,
+    reload::{Services, SharedServices},
+    stats::{end_process_block, start_process_block, Stats},
+    token::TokenClient,
+};
+
+/// How often the checkpoint of a pipeline is saved. Blocks done since the
+/// last save are processed again after a restart.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Height up to which a pipeline is done with every block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub block_height: u64,
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = std::fs::File::open(path)?;
+        Ok(Some(serde_json::from_reader(std::io::BufReader::new(
+            file,
+        ))?))
+    }
+
+    /// Block after the lowest checkpoint, from which every pipeline gets
+    /// the blocks it isn't done with. `None` when no pipeline has one.
+    pub fn resume_height(checkpoints: &[Option<Self>]) -> Option<u64> {
+        checkpoints
+            .iter()
+            .flatten()
+            .map(|checkpoint| checkpoint.block_height + 1)
+            .min()
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let file = std::fs::File::create(&tmp_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), self)?;
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+/// Filters, routes, enrichment and sinks of a `[pipelines.<name>]` table.
+/// Every pipeline is fed all the blocks, with its own stats and checkpoint.
+/// A pipeline failing on a block is stopped, the others keep going.
+pub struct Pipeline {
+    pub config: Arc<ArcSwap<NesConfig>>,
+    pub services: SharedServices,
+    pub stats: Arc<Mutex<Stats>>,
+    /// Checkpoint found on startup, the blocks up to it are skipped
+    resumed: Option<Checkpoint>,
+    saved: Cell<Option<Checkpoint>>,
+    saved_at: Cell<Instant>,
+    failed: Cell<bool>,
+}
+
+impl Pipeline {
+    pub fn new(
+        config: Arc<ArcSwap<NesConfig>>,
+        stats: Arc<Mutex<Stats>>,
+        token_client: Arc<TokenClient>,
+    ) -> anyhow::Result<Self> {
+        let nes_config = config.load_full();
+        let resumed = Checkpoint::load(&nes_config.checkpoint_path)?;
+        if let Some(checkpoint) = resumed {
+            tracing::info!(
+                target: crate::INDEXER,
+                "Pipeline `{}` resumes after block {}",
+                nes_config.pipeline,
+                checkpoint.block_height
+            );
+        }
+
+        Ok(Self {
+            services: Rc::new(RefCell::new(Rc::new(Services::new(
+                &nes_config,
+                token_client,
+            )?))),
+            config,
+            stats,
+            resumed,
+            saved: Cell::new(resumed),
+            saved_at: Cell::new(Instant::now()),
+            failed: Cell::new(false),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.config.load().pipeline.clone()
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.get()
+    }
+
+    /// Stops the pipeline after it failed on `block_height`. Its
+    /// checkpoint stays below that block, which is sent again when the
+    /// indexer restarts from the interruption.
+    pub fn fail(&self, block_height: u64, err: anyhow::Error) {
+        self.failed.set(true);
+        tracing::error!(
+            target: crate::INDEXER,
+            "Pipeline `{}` stopped on block {}, it resumes after block {:?} on restart: {:?}",
+            self.name(),
+            block_height,
+            self.saved.get().map(|checkpoint| checkpoint.block_height),
+            err
+        );
+    }
+
+    pub async fn handle_message(
+        &self,
+        streamer_message: &near_indexer::StreamerMessage,
+    ) -> anyhow::Result<()> {
+        let block_height = streamer_message.block.header.height;
+        if self.failed.get()
+            || matches!(self.resumed, Some(checkpoint) if block_height <= checkpoint.block_height)
+        {
+            return Ok(());
+        }
+
+        // Config changes, from a reload or the admin API, apply from the next block on
+        let nes_config = self.config.load_full();
+        let services = Rc::clone(&self.services.borrow());
+        start_process_block(&self.stats, block_height).await;
+
+        store_events(
+            streamer_message,
+            &services.producer,
+            &services.topics,
+            &services.enrichers,
+            &nes_config,
+        )
+        .await?;
+
+        end_process_block(&self.stats, block_height).await;
+
+        if self.saved_at.get().elapsed() >= CHECKPOINT_INTERVAL {
+            self.save_checkpoint(&nes_config).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn save_checkpoint(&self, nes_config: &NesConfig) -> anyhow::Result<()> {
+        self.saved_at.set(Instant::now());
+        let saved = self.saved.get().map(|checkpoint| checkpoint.block_height);
+        let block_height = self.stats.lock().await.checkpoint();
+        if let Some(block_height) = block_height.filter(|height| Some(*height) > saved) {
+            let checkpoint = Checkpoint { block_height };
+            checkpoint.save(&nes_config.checkpoint_path)?;
+            self.saved.set(Some(checkpoint));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{configs::NES_CONFIG_FILENAME, test_utils::temp_dir};
+
+    use super::*;
+
+    const NES_TOML: &str = r#"
+[topics]
+prefix="near_events"
+
+[kafka]
+"bootstrap.servers"="localhost:9092"
+
+[pipelines.nft.topics]
+prefix="nft"
+
+[pipelines.nft.filters]
+whitelist_contract_ids=["*.paras.near"]
+
+[pipelines.archive.kafka]
+"bootstrap.servers"="archive:9092"
+"#;
+
+    #[test]
+    fn loads_pipelines() {
+        let home_dir = temp_dir("pipelines");
+        std::fs::write(home_dir.join(NES_CONFIG_FILENAME), NES_TOML).unwrap();
+
+        let pipelines = NesConfig::pipelines(home_dir.clone()).unwrap();
+        let (archive, nft) = (&pipelines[0], &pipelines[1]);
+        assert_eq!(archive.pipeline, "archive");
+        assert_eq!(archive.near_events_topic_prefix, "near_events");
+        assert_eq!(archive.kafka["bootstrap.servers"], "archive:9092");
+        assert_eq!(nft.near_events_topic_prefix, "nft");
+        assert!(nft.whitelist_contract_ids.is_match("x.paras.near"));
+        assert_eq!(nft.kafka["bootstrap.servers"], "localhost:9092");
+        assert_eq!(
+            nft.checkpoint_path,
+            home_dir.join("nes.checkpoint.nft.json")
+        );
+        assert_eq!(nft.runtime_path, home_dir.join("nes.runtime.nft.json"));
+
+        std::fs::remove_dir_all(home_dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_shared_keys() {
+        let home_dir = temp_dir("pipelines-shared-keys");
+        std::fs::write(
+            home_dir.join(NES_CONFIG_FILENAME),
+            format!(
+                "{}\n[pipelines.nft.enrichment]\nmetadata_cache_path=\"nft.json\"\n",
+                NES_TOML
+            ),
+        )
+        .unwrap();
+
+        assert!(NesConfig::pipelines(home_dir.clone()).is_err());
+
+        std::fs::remove_dir_all(home_dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoints() {
+        let home_dir = temp_dir("checkpoints");
+        let path = home_dir.join("nes.checkpoint.json");
+
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+        Checkpoint { block_height: 12 }.save(&path).unwrap();
+        assert_eq!(
+            Checkpoint::load(&path).unwrap(),
+            Some(Checkpoint { block_height: 12 })
+        );
+
+        std::fs::remove_dir_all(home_dir).unwrap();
+    }
+
+    #[test]
+    fn resends_blocks_of_failed_pipelines() {
+        use crate::configs::{RunArgs, SyncModeSubCommand};
+
+        let home_dir = temp_dir("resume");
+        let (failed, running) = (
+            home_dir.join("nes.checkpoint.failed.json"),
+            home_dir.join("nes.checkpoint.running.json"),
+        );
+        // The running pipeline went on after the other one failed on block 11
+        Checkpoint { block_height: 10 }.save(&failed).unwrap();
+        Checkpoint { block_height: 20 }.save(&running).unwrap();
+        let checkpoints = [
+            Checkpoint::load(&running).unwrap(),
+            Checkpoint::load(&failed).unwrap(),
+            None,
+        ];
+        assert_eq!(Checkpoint::resume_height(&checkpoints), Some(11));
+        assert_eq!(Checkpoint::resume_height(&[None]), None);
+
+        let args = |sync_mode| RunArgs {
+            stream_while_syncing: false,
+            sync_mode,
+            concurrency: std::num::NonZeroU16::new(1).unwrap(),
+        };
+        let config = args(SyncModeSubCommand::SyncFromInterruption)
+            .to_indexer_config(home_dir.clone(), Checkpoint::resume_height(&checkpoints));
+        assert!(matches!(
+            config.sync_mode,
+            near_indexer::SyncModeEnum::BlockHeight(11)
+        ));
+        let config =
+            args(SyncModeSubCommand::SyncFromLatest).to_indexer_config(home_dir.clone(), Some(11));
+        assert!(matches!(
+            config.sync_mode,
+            near_indexer::SyncModeEnum::LatestSynced
+        ));
+
+        std::fs::remove_dir_all(home_dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    cell::RefCell,
+    path::Path,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use rdkafka::producer::FutureProducer;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+use crate::{
+    configs::{NesConfig, NES_CONFIG_FILENAME},
+    enrichment::EnricherChain,
+    pipeline::Pipeline,
+    token::TokenClient,
+    topics::TopicManager,
+};
+
+/// What blocks are processed with, built from the config. A block keeps
+/// the services it started with until it is done.
+pub struct Services {
+    pub producer: FutureProducer,
+    pub topics: Arc<TopicManager>,
+    pub enrichers: EnricherChain,
+}
+
+/// Swappable [`Services`]. They hold non-`Send` HTTP clients, which is
+/// fine as blocks are all processed on the actix system thread.
+pub type SharedServices = Rc<RefCell<Rc<Services>>>;
+
+impl Services {
+    pub fn new(nes_config: &NesConfig, token_client: Arc<TokenClient>) -> anyhow::Result<Self> {
+        Ok(Self {
+            producer: nes_config.kafka_config.create()?,
+            topics: Arc::new(TopicManager::new(nes_config)?),
+            enrichers: EnricherChain::new(nes_config, token_client)?,
+        })
+    }
+
+    /// Keeps the Kafka clients of `self` when the Kafka config is unchanged.
+    fn rebuild(
+        &self,
+        nes_config: &NesConfig,
+        token_client: Arc<TokenClient>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            producer: self.producer.clone(),
+            topics: Arc::clone(&self.topics),
+            enrichers: EnricherChain::new(nes_config, token_client)?,
+        })
+    }
+}
+
+/// Keys only read at startup, changing them needs a restart.
+pub fn restart_required(current: &NesConfig, new: &NesConfig) -> Vec<&'static str> {
+    [
+        (
+            "enrichment.metadata_cache_capacity",
+            current.metadata_cache_capacity != new.metadata_cache_capacity,
+        ),
+        (
+            "enrichment.metadata_cache_ttl_secs",
+            current.metadata_cache_ttl_secs != new.metadata_cache_ttl_secs,
+        ),
+        (
+            "enrichment.contract_metadata_cache_capacity",
+            current.contract_metadata_cache_capacity != new.contract_metadata_cache_capacity,
+        ),
+        (
+            "enrichment.metadata_cache_path",
+            current.metadata_cache_path != new.metadata_cache_path,
+        ),
+        ("admin_addr", current.admin_addr != new.admin_addr),
+        (
+            "config_reload_secs",
+            current.config_reload_secs != new.config_reload_secs,
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(key, _)| key)
+    .collect()
+}
+
+/// Loads `nes.toml` again and swaps it in, for every pipeline. Blocks
+/// already being processed finish with the config they started with. When
+/// the Kafka config changed, the clients are rebuilt and the old ones
+/// dropped once those blocks are drained. Existing topics are reconciled
+/// again when `topics.specs` changed. Adding or removing pipelines needs
+/// a restart.
+pub async fn reload(pipelines: &[Pipeline], token_client: &Arc<TokenClient>) -> anyhow::Result<()> {
+    let home_dir = pipelines[0].config.load().home_dir.clone();
+    let new_configs = NesConfig::pipelines(home_dir)?;
+
+    let names: Vec<String> = pipelines.iter().map(Pipeline::name).collect();
+    let new_names: Vec<&String> = new_configs.iter().map(|new| &new.pipeline).collect();
+    if names.iter().ne(new_names.iter().copied()) {
+        warn!(
+            target: crate::INDEXER,
+            "Pipelines {:?} configured, {:?} keep running until a restart", new_names, names
+        );
+    }
+
+    // Every pipeline is rebuilt before any is swapped, so an error keeps them all
+    let mut updates = vec![];
+    for new in new_configs {
+        let pipeline = match pipelines
+            .iter()
+            .find(|pipeline| pipeline.name() == new.pipeline)
+        {
+            Some(pipeline) => pipeline,
+            None => continue,
+        };
+        let current = pipeline.config.load_full();
+        if updates.is_empty() {
+            // Those keys are the same for all pipelines
+            restart_required(&current, &new).iter().for_each(|key| {
+                warn!(
+                    target: crate::INDEXER,
+                    "`{}` changed, it is applied on restart only", key
+                )
+            });
+        }
+
+        let kafka_changed = current.kafka != new.kafka;
+        let new_services = match kafka_changed {
+            true => Services::new(&new, Arc::clone(token_client))?,
+            false => pipeline
+                .services
+                .borrow()
+                .rebuild(&new, Arc::clone(token_client))?,
+        };
+        // A new TopicManager starts with nothing reconciled
+        let specs_changed = !kafka_changed
+            && (current.topic_specs != new.topic_specs
+                || current.reconcile_topics != new.reconcile_topics);
+        updates.push((pipeline, new, new_services, kafka_changed, specs_changed));
+    }
+
+    let mut drained = vec![];
+    for (pipeline, new, new_services, kafka_changed, specs_changed) in updates {
+        if specs_changed {
+            new_services.topics.forget_reconciled().await;
+        }
+        pipeline.config.store(Arc::new(new));
+        let old_services = pipeline.services.replace(Rc::new(new_services));
+        if kafka_changed {
+            drained.push((pipeline.name(), old_services));
+        }
+    }
+    info!(target: crate::INDEXER, "Reloaded {}", NES_CONFIG_FILENAME);
+
+    for (name, old_services) in drained {
+        while Rc::strong_count(&old_services) > 1 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        info!(
+            target: crate::INDEXER,
+            "Blocks of pipeline `{}` using the previous Kafka config are drained", name
+        );
+    }
+
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Reloads the config on SIGHUP and, every `config_reload_secs`, when
+/// `nes.toml` was modified. An invalid config is reported and ignored.
+pub async fn watch_config(
+    pipelines: Rc<Vec<Pipeline>>,
+    token_client: Arc<TokenClient>,
+) -> anyhow::Result<()> {
+    let config = pipelines[0].config.load_full();
+    let path = config.home_dir.join(NES_CONFIG_FILENAME);
+    let poll_secs = config.config_reload_secs;
+    let mut last_modified = modified(&path);
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut poll = tokio::time::interval(Duration::from_secs(poll_secs.max(1)));
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!(target: crate::INDEXER, "SIGHUP received"),
+            _ = poll.tick() => {
+                let modified = modified(&path);
+                if poll_secs == 0 || modified == last_modified {
+                    continue;
+                }
+                info!(target: crate::INDEXER, "{} changed", NES_CONFIG_FILENAME);
+            }
+        }
+        last_modified = modified(&path);
+
+        if let Err(err) = reload(&pipelines, &token_client).await {
+            warn!("Keeping the current config, could not reload: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn reloaded_config() {
+        let home_dir = temp_dir("reload");
+        let path = home_dir.join(NES_CONFIG_FILENAME);
+        let nes_toml = r#"
+[topics]
+partitions=1
+
+[kafka]
+"bootstrap.servers"="localhost:9092"
+"#;
+
+        std::fs::write(&path, nes_toml).unwrap();
+        let current = NesConfig::pipelines(home_dir.clone()).unwrap().remove(0);
+        assert_eq!(current.config_reload_secs, 10);
+        assert_eq!(current.near_events_topic_prefix, "near_events");
+        assert!(current.stats_enabled);
+
+        std::fs::write(
+            &path,
+            format!("admin_addr=\"127.0.0.1:3030\"\n{}", nes_toml),
+        )
+        .unwrap();
+        let new = NesConfig::pipelines(home_dir.clone()).unwrap().remove(0);
+        assert_eq!(restart_required(&current, &new), ["admin_addr"]);
+
+        std::fs::write(
+            &path,
+            format!("near_events_topic_prefix=\"\"\n{}", nes_toml),
+        )
+        .unwrap();
+        let new = NesConfig::pipelines(home_dir.clone()).unwrap().remove(0);
+        assert_eq!(new.near_events_topic_prefix, "");
+
+        std::fs::write(&path, nes_toml.replace("partitions=1", "partitions=0")).unwrap();
+        assert!(NesConfig::pipelines(home_dir.clone()).is_err());
+
+        std::fs::remove_dir_all(home_dir).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use actix_tls::connect::{Connector, Resolve, Resolver};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{cache::TtlCache, configs::NesConfig, token::TokenMetadata};
+
+/// Fetches the raw content behind a URI.
+#[async_trait(?Send)]
+pub trait Fetcher {
+    fn supports(&self, uri: &str) -> bool;
+
+    async fn fetch(&self, uri: &str) -> anyhow::Result<Vec<u8>>;
+}
+
+/// How long a reference that could not be resolved is not fetched again
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+
+/// Resolves hosts to public addresses only. The client connects to the
+/// addresses checked here, a second lookup could return other ones.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+            match addrs.iter().find(|addr| !is_public(addr.ip())) {
+                Some(addr) => {
+                    Err(format!("{} resolves to the non-public address {}", host, addr.ip()).into())
+                }
+                None => Ok(addrs),
+            }
+        })
+    }
+}
+
+/// Fetches `https://` URIs. The URIs come from token metadata, anyone can
+/// set them, so hosts resolving to private or loopback addresses are refused
+/// and redirects, which could lead to one, are not followed.
+pub struct HttpFetcher {
+    client: awc::Client,
+    max_bytes: usize,
+    public_only: bool,
+}
+
+impl HttpFetcher {
+    pub fn new(max_bytes: usize) -> Self {
+        let connector = awc::Connector::new()
+            .connector(Connector::new(Resolver::custom(PublicResolver)).service());
+        Self {
+            client: awc::Client::builder()
+                .disable_redirects()
+                .connector(connector)
+                .finish(),
+            max_bytes,
+            public_only: true,
+        }
+    }
+
+    /// Fetches from any host over http or https, for URIs of the config.
+    pub fn trusted(max_bytes: usize) -> Self {
+        Self {
+            client: awc::Client::default(),
+            max_bytes,
+            public_only: false,
+        }
+    }
+
+    /// Host names are checked by [`PublicResolver`] when connecting, IP
+    /// addresses are not resolved so they are checked here.
+    fn check_public(uri: &str) -> anyhow::Result<()> {
+        let (host, _) =
+            host_port(uri).ok_or_else(|| anyhow::anyhow!("{} is not an https URI", uri))?;
+        match host.parse::<IpAddr>() {
+            Ok(ip) if !is_public(ip) => anyhow::bail!("{} is not a public address", uri),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_status(uri: &str, status: awc::http::StatusCode) -> anyhow::Result<()> {
+    if status.is_redirection() {
+        anyhow::bail!("GET {} redirects, redirects are not followed", uri);
+    }
+    if !status.is_success() {
+        anyhow::bail!("GET {} returned {}", uri, status);
+    }
+
+    Ok(())
+}
+
+/// Host and port of an `https://` URI.
+fn host_port(uri: &str) -> Option<(String, u16)> {
+    let rest = uri.strip_prefix("https://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(ipv6) => {
+            let (host, rest) = ipv6.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 443,
+    };
+
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || a == 0
+                // Shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local and link-local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+                && ip.to_ipv4().map(|ip| is_public(ip.into())).unwrap_or(true)
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Fetcher for HttpFetcher {
+    fn supports(&self, uri: &str) -> bool {
+        uri.starts_with("https://") || (!self.public_only && uri.starts_with("http://"))
+    }
+
+    async fn fetch(&self, uri: &str) -> anyhow::Result<Vec<u8>> {
+        if self.public_only {
+            Self::check_public(uri)?;
+        }
+
+        let mut response = self
+            .client
+            .get(uri)
+            .send()
+            .await
+            .map_err(|err| anyhow::anyhow!("GET {} failed: {}", uri, err))?;
+        check_status(uri, response.status())?;
+
+        let body = response
+            .body()
+            .limit(self.max_bytes)
+            .await
+            .map_err(|err| anyhow::anyhow!("GET {} body: {}", uri, err))?;
+        Ok(body.to_vec())
+    }
+}
+
+/// Fetches `ipfs://<cid>/<path>` through an HTTP gateway.
+pub struct IpfsFetcher {
+    gateway: String,
+    http: HttpFetcher,
+}
+
+impl IpfsFetcher {
+    pub fn new(gateway: &str, max_bytes: usize) -> Self {
+        Self {
+            gateway: gateway.trim_end_matches('/').to_string(),
+            http: HttpFetcher::trusted(max_bytes),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Fetcher for IpfsFetcher {
+    fn supports(&self, uri: &str) -> bool {
+        uri.starts_with("ipfs://")
+    }
+
+    async fn fetch(&self, uri: &str) -> anyhow::Result<Vec<u8>> {
+        let path = uri.trim_start_matches("ipfs://");
+        self.http.fetch(&format!("{}/{}", self.gateway, path)).await
+    }
+}
+
+/// Serves fixed content, for tests.
+#[cfg(test)]
+pub struct StubFetcher {
+    pub responses: std::collections::HashMap<String, Vec<u8>>,
+}
+
+#[cfg(test)]
+#[async_trait(?Send)]
+impl Fetcher for StubFetcher {
+    fn supports(&self, _uri: &str) -> bool {
+        true
+    }
+
+    async fn fetch(&self, uri: &str) -> anyhow::Result<Vec<u8>> {
+        self.responses
+            .get(uri)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{} not found", uri))
+    }
+}
+
+/// Resolved references, keyed by (uri, reference_hash)
+pub type ReferenceCache = TtlCache<(String, Option<String>), serde_json::Value>;
+
+/// Resolves the off-chain `reference` JSON of token metadata. The content
+/// is checked against `reference_hash` (base64 encoded sha256) when set.
+/// Failures are remembered for a minute, so a dead host doesn't cost a
+/// timeout for every token pointing to it.
+pub struct ReferenceResolver {
+    fetchers: Vec<Box<dyn Fetcher>>,
+    cache: ReferenceCache,
+    failures: TtlCache<(String, Option<String>), String>,
+    timeout: Duration,
+}
+
+impl ReferenceResolver {
+    pub fn new(nes_config: &NesConfig) -> Self {
+        Self::with_fetchers(
+            vec![
+                Box::new(HttpFetcher::new(nes_config.reference_max_bytes)),
+                Box::new(IpfsFetcher::new(
+                    &nes_config.reference_ipfs_gateway,
+                    nes_config.reference_max_bytes,
+                )),
+            ],
+            TtlCache::new(
+                nes_config.reference_cache_capacity,
+                Duration::from_secs(nes_config.metadata_cache_ttl_secs),
+            ),
+            Duration::from_millis(nes_config.reference_timeout_ms),
+        )
+    }
+
+    pub fn with_fetchers(
+        fetchers: Vec<Box<dyn Fetcher>>,
+        cache: ReferenceCache,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            fetchers,
+            failures: TtlCache::new(cache.capacity(), FAILURE_TTL),
+            cache,
+            timeout,
+        }
+    }
+
+    /// Absolute URI of `reference`. As in NEP-177, a relative reference
+    /// is resolved against the contract's `base_uri`.
+    pub fn reference_uri(reference: &str, base_uri: Option<&str>) -> Option<String> {
+        if reference.contains("://") {
+            return Some(reference.to_string());
+        }
+        base_uri.map(|base_uri| {
+            format!(
+                "{}/{}",
+                base_uri.trim_end_matches('/'),
+                reference.trim_start_matches('/')
+            )
+        })
+    }
+
+    pub async fn resolve(
+        &self,
+        uri: &str,
+        reference_hash: Option<&str>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let cache_key = (uri.to_string(), reference_hash.map(str::to_string));
+        if let Some(value) = self.cache.get(&cache_key) {
+            return Ok(value);
+        }
+        if let Some(err) = self.failures.get(&cache_key) {
+            anyhow::bail!("{} failed recently: {}", uri, err);
+        }
+
+        match self.fetch(uri, reference_hash).await {
+            Ok(value) => {
+                self.cache.insert(cache_key, value.clone());
+                Ok(value)
+            }
+            Err(err) => {
+                self.failures.insert(cache_key, err.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    async fn fetch(
+        &self,
+        uri: &str,
+        reference_hash: Option<&str>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let fetcher = self
+            .fetchers
+            .iter()
+            .find(|fetcher| fetcher.supports(uri))
+            .ok_or_else(|| anyhow::anyhow!("No fetcher for {}", uri))?;
+        let content = tokio::time::timeout(self.timeout, fetcher.fetch(uri))
+            .await
+            .map_err(|_| anyhow::anyhow!("Fetching {} timed out", uri))??;
+
+        if let Some(reference_hash) = reference_hash {
+            let expected = base64::decode(reference_hash)?;
+            if Sha256::digest(&content).as_slice() != expected.as_slice() {
+                anyhow::bail!("{} does not match reference_hash {}", uri, reference_hash);
+            }
+        }
+
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// The `reference` JSON of `metadata`, `None` when there is none or
+    /// it could not be resolved.
+    pub async fn resolve_metadata(
+        &self,
+        metadata: &TokenMetadata,
+        base_uri: Option<&str>,
+    ) -> Option<serde_json::Value> {
+        let uri = Self::reference_uri(metadata.reference.as_deref()?, base_uri)?;
+
+        match self.resolve(&uri, metadata.reference_hash.as_deref()).await {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Could not resolve reference {}: {:?}", uri, err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_references() {
+        let content = br#"{"attributes":[{"trait_type":"eyes","value":"blue"}]}"#.to_vec();
+        let hash = base64::encode(Sha256::digest(&content));
+        let fetcher = StubFetcher {
+            responses: [("https://arweave.net/abc".to_string(), content)].into(),
+        };
+        let resolver = ReferenceResolver::with_fetchers(
+            vec![Box::new(fetcher)],
+            TtlCache::new(10, Duration::from_secs(60)),
+            Duration::from_secs(1),
+        );
+
+        assert_eq!(
+            ReferenceResolver::reference_uri("abc", Some("https://arweave.net/")),
+            Some("https://arweave.net/abc".to_string())
+        );
+        assert_eq!(ReferenceResolver::reference_uri("abc", None), None);
+
+        actix::System::new().block_on(async {
+            let value = resolver
+                .resolve("https://arweave.net/abc", Some(&hash))
+                .await
+                .unwrap();
+            assert_eq!(value["attributes"][0]["value"], "blue");
+
+            let mismatch = resolver
+                .resolve("https://arweave.net/abc", Some(&base64::encode([0u8; 32])))
+                .await;
+            assert!(mismatch.is_err());
+
+            assert!(resolver
+                .resolve("https://arweave.net/x", None)
+                .await
+                .is_err());
+            let failed = resolver.resolve("https://arweave.net/x", None).await;
+            assert!(format!("{:?}", failed.unwrap_err()).contains("failed recently"));
+
+            let http = HttpFetcher::new(1024);
+            assert!(!http.supports("http://arweave.net/abc"));
+            assert!(HttpFetcher::trusted(1024).supports("http://127.0.0.1:8080/ipfs"));
+            assert!(PublicResolver.lookup("localhost", 443).await.is_err());
+        });
+
+        assert!(HttpFetcher::check_public("https://127.0.0.1/abc").is_err());
+        assert!(HttpFetcher::check_public("https://[::1]:8443/abc").is_err());
+        assert!(HttpFetcher::check_public("https://10.1.2.3/abc").is_err());
+        assert!(HttpFetcher::check_public("https://arweave.net/abc").is_ok());
+
+        // A public host redirecting to the metadata endpoint of the cloud
+        let uri = "https://arweave.net/abc";
+        let redirect = check_status(uri, awc::http::StatusCode::FOUND);
+        assert!(format!("{:?}", redirect.unwrap_err()).contains("not followed"));
+        assert!(check_status(uri, awc::http::StatusCode::OK).is_ok());
+
+        assert_eq!(
+            host_port("https://user@arweave.net:8443/abc?x"),
+            Some(("arweave.net".to_string(), 8443))
+        );
+        assert_eq!(host_port("http://arweave.net/abc"), None);
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(!is_public("100.64.0.1".parse().unwrap()));
+        assert!(!is_public("::ffff:192.168.0.1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+    }
+}
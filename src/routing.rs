@@ -0,0 +1,238 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    configs::NesConfig,
+    event_types::{NearEvent, PartitionKey},
+    expr::Expr,
+    matcher::Pattern,
+    template::Template,
+};
+
+/// A routing rule from the `[[routes]]` table of `nes.toml`. The first rule
+/// matching an event decides where it is sent; events matching no rule
+/// use the default `{prefix}.{standard}.{event}` topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRule {
+    pub contract: Option<Pattern>,
+    pub standard: Option<Pattern>,
+    pub event: Option<Pattern>,
+    pub version: Option<Pattern>,
+    /// Expression over the event, e.g. `data[*].new_owner_id == "market.near"`
+    pub filter: Option<Expr>,
+
+    /// Topic templates, e.g. `{network}.{contract}.{standard}.{event}`.
+    /// Defaults to the `{prefix}.{standard}.{event}` topic when omitted.
+    #[serde(default, deserialize_with = "topic_templates")]
+    pub topics: Option<Vec<Template>>,
+    pub partition_key: Option<PartitionKey>,
+    #[serde(default = "default_true")]
+    pub all_topic: bool,
+    #[serde(default = "default_true")]
+    pub metadata: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn topic_templates<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<Template>>, D::Error> {
+    let topics = Option::<Vec<Template>>::deserialize(deserializer)?;
+    topics
+        .iter()
+        .flatten()
+        .try_for_each(Template::check_topic)
+        .map_err(serde::de::Error::custom)?;
+
+    Ok(topics)
+}
+
+impl RouteRule {
+    /// Whether the rule matches `event`, `context` being the event as
+    /// seen by filter expressions.
+    pub fn matches(&self, event: &NearEvent, context: &Value) -> bool {
+        let contract_account_id = event
+            .emit_info
+            .as_ref()
+            .map(|emit_info| emit_info.contract_account_id.as_str())
+            .unwrap_or_default();
+
+        let matches = |pattern: &Option<Pattern>, value: &str| {
+            pattern
+                .as_ref()
+                .map(|pattern| pattern.is_match(value))
+                .unwrap_or(true)
+        };
+
+        matches(&self.contract, contract_account_id)
+            && matches(&self.standard, &event.standard)
+            && matches(&self.event, &event.event)
+            && matches(&self.version, &event.version)
+            && self
+                .filter
+                .as_ref()
+                .map(|filter| filter.is_match(context))
+                .unwrap_or(true)
+    }
+
+    /// Identifies the rule in the admin API, derived from its content so
+    /// that it stays the same across reloads and other route changes.
+    pub fn id(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        Sha256::digest(json.as_bytes())[..6]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub(crate) fn bind(&mut self, network: &str, prefix: &str) {
+        if let Some(topics) = self.topics.as_mut() {
+            topics
+                .iter_mut()
+                .for_each(|topic| *topic = topic.bind(network, prefix));
+        }
+    }
+}
+
+/// Where a single event goes.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub topics: Vec<String>,
+    pub partition_key: PartitionKey,
+    pub all_topic: bool,
+    pub metadata: bool,
+}
+
+impl Route {
+    pub fn metadata_topics(&self) -> Vec<String> {
+        if !self.metadata {
+            return vec![];
+        }
+        self.topics
+            .iter()
+            .map(|topic| format!("{}_metadata", topic))
+            .collect()
+    }
+}
+
+pub fn route_event(nes_config: &NesConfig, event: &NearEvent) -> Route {
+    let default_topic = event.to_topic(&nes_config.near_events_topic_prefix);
+
+    // Serialized once for the filters of all the rules
+    let context = match nes_config.routes.iter().any(|rule| rule.filter.is_some()) {
+        true => Expr::context(event),
+        false => Value::Null,
+    };
+
+    match nes_config
+        .routes
+        .iter()
+        .find(|rule| rule.matches(event, &context))
+    {
+        Some(rule) => Route {
+            topics: match &rule.topics {
+                Some(topics) => topics.iter().map(|topic| topic.render(event)).collect(),
+                None => vec![default_topic],
+            },
+            partition_key: rule
+                .partition_key
+                .clone()
+                .unwrap_or_else(|| nes_config.event_topic_partition_key.clone()),
+            all_topic: rule.all_topic,
+            metadata: rule.metadata,
+        },
+        None => Route {
+            topics: vec![default_topic],
+            partition_key: nes_config.event_topic_partition_key.clone(),
+            all_topic: true,
+            metadata: true,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_types::EmitInfo;
+
+    fn event(contract: &str, standard: &str, event: &str, owner_id: &str) -> NearEvent {
+        let json = serde_json::json!({
+            "standard": standard,
+            "version": "1.0.0",
+            "event": event,
+            "data": [{"owner_id": owner_id, "token_ids": ["7"]}],
+        });
+        let mut event: NearEvent = serde_json::from_value(json).unwrap();
+        event.emit_info = Some(EmitInfo {
+            contract_account_id: contract.to_string(),
+            ..Default::default()
+        });
+        event
+    }
+
+    #[test]
+    fn routes_events() {
+        let nes_config: NesConfig = serde_json::from_value(serde_json::json!({
+            "routes": [
+                {
+                    "contract": "*.paras.near",
+                    "filter": "data[*].owner_id == \"market.near\"",
+                    "topics": ["market.{contract}"],
+                    "partition_key": "owner",
+                    "all_topic": false,
+                },
+                {
+                    "contract": "*.paras.near",
+                    "topics": ["paras.{event}", "nft.{standard}"],
+                    "metadata": false,
+                },
+                {"standard": "nep141", "partition_key": "receipt_id"},
+                {"standard": "nep*", "topics": ["never"]},
+            ],
+        }))
+        .unwrap();
+
+        // The filter of the first rule doesn't match, the second one does
+        let route = route_event(
+            &nes_config,
+            &event("x.paras.near", "nep171", "nft_mint", "a.near"),
+        );
+        assert_eq!(route.topics, ["paras.nft_mint", "nft.nep171"]);
+        assert_eq!(route.partition_key, PartitionKey::Contract);
+        assert!(route.all_topic);
+        assert!(route.metadata_topics().is_empty());
+
+        let route = route_event(
+            &nes_config,
+            &event("x.paras.near", "nep171", "nft_mint", "market.near"),
+        );
+        assert_eq!(route.topics, ["market.x.paras.near"]);
+        assert_eq!(route.partition_key, PartitionKey::Owner);
+        assert!(!route.all_topic);
+        assert_eq!(route.metadata_topics(), ["market.x.paras.near_metadata"]);
+
+        // A rule without topics uses the default one
+        let route = route_event(
+            &nes_config,
+            &event("token.near", "nep141", "ft_transfer", "a.near"),
+        );
+        assert_eq!(route.topics, ["near_events.nep141.ft_transfer"]);
+        assert_eq!(route.partition_key, PartitionKey::ReceiptId);
+
+        // No rule matches
+        let route = route_event(
+            &nes_config,
+            &event("game.near", "game", "level_up", "a.near"),
+        );
+        assert_eq!(route.topics, ["near_events.game.level_up"]);
+        assert_eq!(route.partition_key, PartitionKey::Contract);
+        assert!(route.all_topic);
+        assert_eq!(
+            route.metadata_topics(),
+            ["near_events.game.level_up_metadata"]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use itertools::Itertools;
+use toml::Value;
+
+/// `nes.toml` sections, as `(section, key, NesConfig field)`. `[kafka]`
+/// holds the librdkafka properties and isn't listed.
+pub const SECTION_KEYS: &[(&str, &str, &str)] = &[
+    ("topics", "prefix", "near_events_topic_prefix"),
+    ("topics", "all_topic", "near_events_all_topic"),
+    ("topics", "network", "network"),
+    ("topics", "partitions", "new_topic_partitions"),
+    ("topics", "replication", "new_topic_replication"),
+    ("topics", "force_create", "force_create_new_topic"),
+    ("topics", "reconcile", "reconcile_topics"),
+    ("topics", "cache_refresh_secs", "topic_cache_refresh_secs"),
+    ("topics", "specs", "topic_specs"),
+    (
+        "filters",
+        "whitelist_contract_ids",
+        "whitelist_contract_ids",
+    ),
+    (
+        "filters",
+        "blacklist_contract_ids",
+        "blacklist_contract_ids",
+    ),
+    (
+        "filters",
+        "whitelist_predecessor_ids",
+        "whitelist_predecessor_ids",
+    ),
+    (
+        "filters",
+        "blacklist_predecessor_ids",
+        "blacklist_predecessor_ids",
+    ),
+    ("filters", "whitelist_signer_ids", "whitelist_signer_ids"),
+    ("filters", "blacklist_signer_ids", "blacklist_signer_ids"),
+    ("filters", "include_events", "include_events"),
+    ("filters", "exclude_events", "exclude_events"),
+    ("enrichment", "metadata", "enrich_metadata"),
+    ("enrichment", "accounts", "enrich_accounts"),
+    ("enrichment", "payouts", "payouts"),
+    ("enrichment", "view_calls", "view_calls"),
+    (
+        "enrichment",
+        "metadata_cache_capacity",
+        "metadata_cache_capacity",
+    ),
+    (
+        "enrichment",
+        "metadata_cache_ttl_secs",
+        "metadata_cache_ttl_secs",
+    ),
+    (
+        "enrichment",
+        "contract_metadata_cache_capacity",
+        "contract_metadata_cache_capacity",
+    ),
+    ("enrichment", "metadata_cache_path", "metadata_cache_path"),
+    (
+        "enrichment",
+        "metadata_cache_persist_secs",
+        "metadata_cache_persist_secs",
+    ),
+    (
+        "enrichment",
+        "metadata_block_fallback",
+        "metadata_block_fallback",
+    ),
+    ("enrichment", "metadata_concurrency", "metadata_concurrency"),
+    (
+        "enrichment",
+        "metadata_batch_threshold",
+        "metadata_batch_threshold",
+    ),
+    ("enrichment", "metadata_batch_size", "metadata_batch_size"),
+    (
+        "enrichment",
+        "metadata_batch_owner_pages",
+        "metadata_batch_owner_pages",
+    ),
+    (
+        "enrichment",
+        "metadata_batch_methods",
+        "metadata_batch_methods",
+    ),
+    ("enrichment", "resolve_references", "resolve_references"),
+    (
+        "enrichment",
+        "reference_ipfs_gateway",
+        "reference_ipfs_gateway",
+    ),
+    ("enrichment", "reference_timeout_ms", "reference_timeout_ms"),
+    ("enrichment", "reference_max_bytes", "reference_max_bytes"),
+    (
+        "enrichment",
+        "reference_cache_capacity",
+        "reference_cache_capacity",
+    ),
+    ("stats", "enabled", "stats_enabled"),
+    ("sinks", "routes", "routes"),
+    (
+        "sinks",
+        "all_topic_partition_key",
+        "all_topic_partition_key",
+    ),
+    (
+        "sinks",
+        "event_topic_partition_key",
+        "event_topic_partition_key",
+    ),
+    (
+        "sinks",
+        "metadata_topic_partition_key",
+        "metadata_topic_partition_key",
+    ),
+];
+
+/// `nes.toml` keys outside of any section.
+pub const TOP_LEVEL_KEYS: &[&str] = &[
+    "admin_addr",
+    "admin_token",
+    "admin_audit_log",
+    "config_reload_secs",
+];
+
+/// What is wrong with `nes.toml`, without preventing it from loading.
+#[derive(Debug, Default)]
+pub struct ConfigWarnings {
+    pub unknown_keys: Vec<String>,
+    /// Top-level keys with their `section.key` replacement
+    pub deprecated_keys: Vec<(String, String)>,
+    /// Keys replaced by the runtime overrides of the admin API
+    pub overridden_keys: Vec<String>,
+}
+
+/// `NesConfig` field set by `section.key`.
+pub fn field(section: &str, key: &str) -> Option<&'static str> {
+    SECTION_KEYS
+        .iter()
+        .find(|(s, k, _)| *s == section && *k == key)
+        .map(|(_, _, field)| *field)
+}
+
+/// Moves the keys of the sections to the top-level fields `NesConfig`
+/// deserializes. The former top-level keys are still read, a section
+/// key wins over them.
+pub fn flatten(config: &mut Value) -> ConfigWarnings {
+    let mut warnings = ConfigWarnings::default();
+    let table = match config.as_table_mut() {
+        Some(table) => table,
+        None => return warnings,
+    };
+
+    SECTION_KEYS.iter().for_each(|(section, key, field)| {
+        if table.contains_key(*field) {
+            warnings
+                .deprecated_keys
+                .push((field.to_string(), format!("{}.{}", section, key)));
+        }
+    });
+
+    let sections = SECTION_KEYS.iter().map(|(section, _, _)| *section).unique();
+    for section in sections {
+        let values = match table.remove(section) {
+            Some(Value::Table(values)) => values,
+            Some(value) => {
+                table.insert(section.to_string(), value);
+                continue;
+            }
+            None => continue,
+        };
+        for (key, value) in values {
+            match field(section, &key) {
+                Some(field) => {
+                    table.insert(field.to_string(), value);
+                }
+                None => warnings.unknown_keys.push(format!("{}.{}", section, key)),
+            }
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flattens_sections() {
+        let mut config: Value = toml::from_str(
+            r#"
+stats_enabled=false
+enrich_metadata=true
+
+[topics]
+prefix="near_events"
+
+[stats]
+enabled=true
+enabeld=true
+
+[[sinks.routes]]
+standard="nep171"
+
+[kafka]
+"bootstrap.servers"="localhost:9092"
+"#,
+        )
+        .unwrap();
+        let warnings = flatten(&mut config);
+
+        assert_eq!(
+            config["near_events_topic_prefix"].as_str(),
+            Some("near_events")
+        );
+        assert_eq!(config["stats_enabled"].as_bool(), Some(true));
+        assert_eq!(config["enrich_metadata"].as_bool(), Some(true));
+        assert_eq!(config["routes"][0]["standard"].as_str(), Some("nep171"));
+        assert!(config.get("stats").is_none());
+        assert!(config.get("kafka").is_some());
+        assert_eq!(warnings.unknown_keys, ["stats.enabeld"]);
+        assert_eq!(
+            warnings.deprecated_keys,
+            [
+                (
+                    "enrich_metadata".to_string(),
+                    "enrichment.metadata".to_string()
+                ),
+                ("stats_enabled".to_string(), "stats.enabled".to_string())
+            ]
+        );
+        assert_eq!(field("topics", "specs"), Some("topic_specs"));
+    }
+}
@@ -1,8 +1,11 @@
 use std::{collections::BTreeSet, sync::Arc};
 
+use arc_swap::ArcSwap;
 use near_indexer::near_primitives::types;
 use tokio::sync::Mutex;
 
+use crate::configs::{NesConfig, DEFAULT_PIPELINE};
+
 #[derive(Debug, Clone)]
 pub struct Stats {
     pub block_heights_processing: BTreeSet<u64>,
@@ -18,11 +21,25 @@ impl Stats {
             last_processed_block_height: 0,
         }
     }
+
+    /// Height up to which every block is processed. Blocks are processed
+    /// concurrently, some below the last processed one may still be running.
+    pub fn checkpoint(&self) -> Option<u64> {
+        if self.blocks_processed_count == 0 {
+            return None;
+        }
+
+        match self.block_heights_processing.iter().next() {
+            Some(lowest) => Some((lowest - 1).min(self.last_processed_block_height)),
+            None => Some(self.last_processed_block_height),
+        }
+    }
 }
 
 pub async fn stats_logger(
     stats: Arc<Mutex<Stats>>,
     view_client: actix::Addr<near_client::ViewClientActor>,
+    nes_config: Arc<ArcSwap<NesConfig>>,
 ) {
     let interval_secs = 10;
     let mut prev_blocks_processed_count: u64 = 0;
@@ -33,6 +50,13 @@ pub async fn stats_logger(
         let stats_copy = stats_lock.clone();
         drop(stats_lock);
 
+        // `stats_enabled` can be toggled by a config reload
+        let config = nes_config.load_full();
+        if !config.stats_enabled {
+            prev_blocks_processed_count = stats_copy.blocks_processed_count;
+            continue;
+        }
+
         let block_processing_speed: f64 = ((stats_copy.blocks_processed_count
             - prev_blocks_processed_count) as f64)
             / (interval_secs as f64);
@@ -53,7 +77,11 @@ pub async fn stats_logger(
 
         tracing::info!(
             target: crate::INDEXER,
-            "# {} | Blocks processing: {} | Blocks done: {}. Bps {:.2} b/s {}",
+            "{}# {} | Blocks processing: {} | Blocks done: {}. Bps {:.2} b/s {}",
+            match config.pipeline.as_str() {
+                DEFAULT_PIPELINE => "".to_string(),
+                pipeline => format!("[{}] ", pipeline),
+            },
             stats_copy.last_processed_block_height,
             stats_copy.block_heights_processing.len(),
             stats_copy.blocks_processed_count,
@@ -92,6 +120,26 @@ pub async fn end_process_block(stats: &Arc<Mutex<Stats>>, block_height: u64) {
     let mut stats_lock = stats.lock().await;
     stats_lock.block_heights_processing.remove(&block_height);
     stats_lock.blocks_processed_count += 1;
-    stats_lock.last_processed_block_height = block_height;
+    stats_lock.last_processed_block_height =
+        block_height.max(stats_lock.last_processed_block_height);
     drop(stats_lock);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint() {
+        let mut stats = Stats::new();
+        assert_eq!(stats.checkpoint(), None);
+
+        stats.block_heights_processing.extend([10, 11]);
+        stats.blocks_processed_count = 1;
+        stats.last_processed_block_height = 12;
+        assert_eq!(stats.checkpoint(), Some(9));
+
+        stats.block_heights_processing.clear();
+        assert_eq!(stats.checkpoint(), Some(12));
+    }
+}
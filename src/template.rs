@@ -0,0 +1,373 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{event_types::NearEvent, expr::Path};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Var {
+    Contract,
+    Standard,
+    Version,
+    Event,
+    TokenId,
+    Owner,
+    ReceiptId,
+    BlockHeight,
+    ShardId,
+    EventId,
+    Network,
+    Prefix,
+    /// Path into the event data, e.g. `data[0].price`
+    Data(Path),
+}
+
+impl FromStr for Var {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s == "data" || s.starts_with("data.") || s.starts_with("data[") => {
+                Ok(Self::Data(s.parse()?))
+            }
+            "contract" => Ok(Self::Contract),
+            "standard" => Ok(Self::Standard),
+            "version" => Ok(Self::Version),
+            "event" => Ok(Self::Event),
+            "token_id" => Ok(Self::TokenId),
+            "owner" => Ok(Self::Owner),
+            "receipt_id" => Ok(Self::ReceiptId),
+            "block_height" => Ok(Self::BlockHeight),
+            "shard_id" => Ok(Self::ShardId),
+            "event_id" => Ok(Self::EventId),
+            "network" => Ok(Self::Network),
+            "prefix" => Ok(Self::Prefix),
+            _ => anyhow::bail!("Unknown template variable `{{{}}}`", s),
+        }
+    }
+}
+
+impl Var {
+    /// Per-token and per-receipt variables would make a topic for every event.
+    fn is_topic_safe(&self) -> bool {
+        matches!(
+            self,
+            Self::Contract
+                | Self::Standard
+                | Self::Version
+                | Self::Event
+                | Self::Network
+                | Self::Prefix
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+}
+
+/// A string with `{variable}` placeholders filled from an event,
+/// e.g. `{contract}:{token_id}`, or `{data[0].price}` for the event data.
+/// `{{` and `}}` stand for `{` and `}`. Unknown variables are rejected
+/// when the template is parsed, so a typo fails on config load.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Replaces the deployment-wide `{network}` and `{prefix}` variables,
+    /// which are known once the config is loaded.
+    pub fn bind(&self, network: &str, prefix: &str) -> Self {
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Var(Var::Network) => Segment::Literal(network.to_string()),
+                Segment::Var(Var::Prefix) => Segment::Literal(prefix.to_string()),
+                _ => segment.clone(),
+            })
+            .collect();
+
+        Self {
+            source: self.source.clone(),
+            segments,
+        }
+    }
+
+    /// Rejects variables that can't be used in a topic name, only
+    /// `{contract}`, `{standard}`, `{version}`, `{event}`, `{network}`
+    /// and `{prefix}` can.
+    pub fn check_topic(&self) -> anyhow::Result<()> {
+        match self.segments.iter().find(|segment| match segment {
+            Segment::Var(var) => !var.is_topic_safe(),
+            Segment::Literal(_) => false,
+        }) {
+            Some(_) => anyhow::bail!(
+                "Topic template `{}` can only use {{contract}}, {{standard}}, {{version}}, {{event}}, {{network}} and {{prefix}}",
+                self.source
+            ),
+            None => Ok(()),
+        }
+    }
+
+    pub fn render(&self, event: &NearEvent) -> String {
+        let emit_info = event.emit_info.as_ref();
+        let context = self.data_context(event);
+
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Var(var) => match var {
+                    Var::Contract => event.to_key(),
+                    Var::Standard => event.standard.clone(),
+                    Var::Version => event.version.clone(),
+                    Var::Event => event.event.clone(),
+                    Var::TokenId => event.token_id().unwrap_or_default(),
+                    Var::Owner => event.owner_id().unwrap_or_default(),
+                    Var::ReceiptId => emit_info
+                        .map(|info| info.receipt_id.clone())
+                        .unwrap_or_default(),
+                    Var::BlockHeight => emit_info
+                        .map(|info| info.block_height.to_string())
+                        .unwrap_or_default(),
+                    Var::ShardId => emit_info
+                        .map(|info| info.shard_id.to_string())
+                        .unwrap_or_default(),
+                    Var::EventId => event.to_event_id().unwrap_or_default(),
+                    Var::Network | Var::Prefix => String::new(),
+                    Var::Data(path) => match path.values(&context).first() {
+                        Some(serde_json::Value::String(s)) => s.clone(),
+                        Some(serde_json::Value::Null) | None => String::new(),
+                        Some(value) => value.to_string(),
+                    },
+                },
+            })
+            .collect()
+    }
+
+    /// Renders to the data value itself when the template is a single
+    /// `{data...}` variable, so numbers and objects keep their type.
+    pub fn render_value(&self, event: &NearEvent) -> serde_json::Value {
+        match self.segments.as_slice() {
+            [Segment::Var(Var::Data(path))] => path
+                .values(&self.data_context(event))
+                .first()
+                .map(|value| (*value).clone())
+                .unwrap_or(serde_json::Value::Null),
+            _ => serde_json::Value::String(self.render(event)),
+        }
+    }
+
+    /// `{"data": ...}` when a `{data...}` variable is used.
+    fn data_context(&self, event: &NearEvent) -> serde_json::Value {
+        let uses_data = self
+            .segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Var(Var::Data(_))));
+        match uses_data {
+            true => serde_json::json!({ "data": event.data }),
+            false => serde_json::Value::Null,
+        }
+    }
+}
+
+/// JSON whose strings are templates, e.g. `{"token_id": "{token_id}"}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum JsonTemplate {
+    String(Template),
+    Array(Vec<JsonTemplate>),
+    Object(Vec<(String, JsonTemplate)>),
+    Value(serde_json::Value),
+}
+
+impl JsonTemplate {
+    pub fn bind(&self, network: &str, prefix: &str) -> Self {
+        match self {
+            Self::String(template) => Self::String(template.bind(network, prefix)),
+            Self::Array(items) => Self::Array(
+                items
+                    .iter()
+                    .map(|item| item.bind(network, prefix))
+                    .collect(),
+            ),
+            Self::Object(entries) => Self::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.bind(network, prefix)))
+                    .collect(),
+            ),
+            Self::Value(value) => Self::Value(value.clone()),
+        }
+    }
+
+    pub fn render(&self, event: &NearEvent) -> serde_json::Value {
+        match self {
+            Self::String(template) => template.render_value(event),
+            Self::Array(items) => items.iter().map(|item| item.render(event)).collect(),
+            Self::Object(entries) => serde_json::Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.render(event)))
+                    .collect(),
+            ),
+            Self::Value(value) => value.clone(),
+        }
+    }
+
+    fn compile(value: serde_json::Value) -> anyhow::Result<Self> {
+        Ok(match value {
+            serde_json::Value::String(s) => Self::String(s.parse()?),
+            serde_json::Value::Array(items) => Self::Array(
+                items
+                    .into_iter()
+                    .map(Self::compile)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            serde_json::Value::Object(entries) => Self::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, Self::compile(value)?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            value => Self::Value(value),
+        })
+    }
+}
+
+impl TryFrom<String> for JsonTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::compile(serde_json::from_str(&value)?)
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push_str(&rest[..1]);
+                rest = &rest[2..];
+            } else if rest.starts_with('}') {
+                literal.push('}');
+                rest = &rest[1..];
+            } else {
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| anyhow::anyhow!("Unclosed `{{` in template `{}`", s))?;
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Var(rest[1..end].parse()?));
+                rest = &rest[end + 1..];
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            segments,
+        })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Template {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_types::EmitInfo;
+
+    #[test]
+    fn render() {
+        let json = r#"{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"old_owner_id":"a.testnet","new_owner_id":"b.testnet","token_ids":["7"]}]}"#;
+        let mut event: NearEvent = serde_json::from_str(json).unwrap();
+        event.emit_info = Some(EmitInfo {
+            contract_account_id: "nft.testnet".to_string(),
+            block_height: 42,
+            ..Default::default()
+        });
+
+        let template: Template = "{contract}/{token_id}@{block_height}:{owner}"
+            .parse()
+            .unwrap();
+        assert_eq!(template.render(&event), "nft.testnet/7@42:b.testnet");
+
+        let template: Template = "{network}.{contract}.{standard}.{event}".parse().unwrap();
+        assert_eq!(
+            template.bind("testnet", "").render(&event),
+            "testnet.nft.testnet.nep171.nft_transfer"
+        );
+
+        let args = JsonTemplate::try_from(
+            r#"{"token_id":"{token_id}","from":["{owner}"],"limit":10}"#.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.render(&event),
+            serde_json::json!({"token_id": "7", "from": ["b.testnet"], "limit": 10})
+        );
+        assert!(JsonTemplate::try_from(r#"{"a":"{unknown}"}"#.to_string()).is_err());
+
+        assert!(template.check_topic().is_ok());
+        let template: Template = "{prefix}.{token_id}".parse().unwrap();
+        assert!(template.check_topic().is_err());
+        assert!(serde_json::from_str::<crate::routing::RouteRule>(
+            r#"{"topics":["{contract}.{owner}"]}"#
+        )
+        .is_err());
+
+        let template: Template = "{data[0].token_ids[0]}@{data[0].memo}".parse().unwrap();
+        assert_eq!(template.render(&event), "7@");
+        assert!(template.check_topic().is_err());
+        assert!("{data[0".parse::<Template>().is_err());
+        let args = JsonTemplate::try_from(
+            r#"{"ids":"{data[0].token_ids}","owner":"{data[0].new_owner_id}","raw":"{{\"a\":1}}"}"#
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.render(&event),
+            serde_json::json!({"ids": ["7"], "owner": "b.testnet", "raw": "{\"a\":1}"})
+        );
+
+        assert!("{unknown}".parse::<Template>().is_err());
+        assert!("{contract".parse::<Template>().is_err());
+    }
+}
@@ -0,0 +1,18 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static TEMP_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// New empty directory for a test, unique across tests and test runs.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "nes-{}-{}-{}",
+        name,
+        std::process::id(),
+        TEMP_DIRS.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -1,13 +1,24 @@
-use futures::{stream::FuturesOrdered, TryStreamExt};
+use futures::{stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use near_client::QueryError;
 use near_indexer::near_primitives::{
-    types::{BlockReference, Finality, FunctionArgs},
-    views::{QueryRequest, QueryResponseKind},
+    types::{BlockId, BlockReference, Finality, FunctionArgs},
+    views::{AccountView, QueryRequest, QueryResponse, QueryResponseKind},
 };
 use serde_json::{from_slice, json};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{cache::TtlCache, configs::NesConfig, event_types::NearEvent, matcher::Pattern};
+
+/// Tokens fetched by `nft_token`, keyed by (contract_account_id, token_id),
+/// with the block height they were read at
+pub type TokenCache = TtlCache<(String, String), (u64, Token)>;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Token {
     pub _id: Option<String>,
@@ -64,82 +75,826 @@ pub struct TokenMetadata {
     pub collection_id: Option<String>,
 }
 
-pub async fn get_nft_token(
-    client: &actix::Addr<near_client::ViewClientActor>,
-    contract_id: &str,
-    token_id: &str,
-) -> anyhow::Result<Option<Token>> {
-    let request = near_client::Query {
-        query_id: String::from("TODO:query_id"),
-        block_reference: BlockReference::Finality(Finality::Final),
-        request: {
-            QueryRequest::CallFunction {
-                account_id: contract_id.parse()?,
-                method_name: "nft_token".to_string(),
-                args: FunctionArgs::from(
-                    json!({
-                        "token_id": token_id,
-                    })
-                    .to_string()
-                    .into_bytes(),
-                ),
+/// NEP-177 `nft_metadata` of a contract.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NftContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+/// NEP-148 `ft_metadata` of a contract.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FtContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+    pub decimals: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ContractMetadata {
+    Nft(NftContractMetadata),
+    Ft(FtContractMetadata),
+}
+
+/// Contract metadata, keyed by contract_account_id, with the block height
+/// it was read at
+pub type ContractMetadataCache = TtlCache<String, (u64, ContractMetadata)>;
+
+/// NEP-199 payout lookup for contracts matching `contract`, from the
+/// `[[payouts]]` table of `nes.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PayoutSpec {
+    pub contract: Pattern,
+    #[serde(default = "default_payout_method")]
+    pub method: String,
+    /// Amount the payout is split from. The default of 10000 gives the
+    /// royalties in basis points.
+    #[serde(default = "default_payout_balance")]
+    pub balance: String,
+    #[serde(default = "default_payout_max_len")]
+    pub max_len_payout: u32,
+}
+
+fn default_payout_method() -> String {
+    String::from("nft_payout")
+}
+
+fn default_payout_balance() -> String {
+    String::from("10000")
+}
+
+fn default_payout_max_len() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Payout {
+    pub payout: HashMap<String, String>,
+}
+
+/// Why a token lookup succeeded or not, so consumers can tell a burned
+/// token from a flaky node.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrichmentStatus {
+    Ok,
+    /// The contract has no such token
+    NotFound,
+    /// The contract is missing or the view call panicked
+    ContractError,
+    /// The node could not answer, e.g. the block state is gone
+    NodeError,
+    /// The view call returned something that isn't a token
+    DecodeError,
+}
+
+impl EnrichmentStatus {
+    fn from_query_error(err: &QueryError) -> Self {
+        match err {
+            QueryError::ContractExecutionError { .. }
+            | QueryError::NoContractCode { .. }
+            | QueryError::UnknownAccount { .. }
+            | QueryError::InvalidAccount { .. } => Self::ContractError,
+            _ => Self::NodeError,
+        }
+    }
+}
+
+pub type TokenLookup = Result<(Token, MetadataSource), EnrichmentStatus>;
+
+/// Where the metadata of a token was read from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataSource {
+    /// State at the block of the event
+    Block,
+    /// Latest final state, the event block is no longer available on the node
+    Latest,
+    /// Cached state of an earlier event block, the token may have changed since
+    Cached,
+}
+
+/// What to do when the node has pruned the state at the event block.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataBlockFallback {
+    /// Query the latest final state and mark the metadata as `latest`
+    #[default]
+    Latest,
+    /// Leave the metadata empty
+    Skip,
+    /// Fail processing the block
+    Fail,
+}
+
+/// The cached value of `key` when it was read at or before `block_height`.
+/// Blocks are enriched concurrently, so the cache may already hold the
+/// state of a later block, which an earlier event must not get.
+fn cached_at<K, V>(cache: &TtlCache<K, (u64, V)>, key: &K, block_height: u64) -> Option<V>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: Clone,
+{
+    cache
+        .get(key)
+        .filter(|(cached_height, _)| *cached_height <= block_height)
+        .map(|(_, value)| value)
+}
+
+/// Caches `value` read at `block_height`, unless the state of a later
+/// block is cached already.
+fn cache_at<K, V>(cache: &TtlCache<K, (u64, V)>, key: K, block_height: u64, value: V)
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: Clone,
+{
+    match cache.get(&key) {
+        Some((cached_height, _)) if cached_height > block_height => {}
+        _ => cache.insert(key, (block_height, value)),
+    }
+}
+
+/// View calls to NFT contracts, with the token cache in front of them.
+pub struct TokenClient {
+    view_client: actix::Addr<near_client::ViewClientActor>,
+    cache: Arc<TokenCache>,
+    contract_cache: ContractMetadataCache,
+    /// Contracts known to support (or not) `nft_tokens_for_owner`
+    owner_enumeration: Mutex<HashMap<String, bool>>,
+}
+
+impl TokenClient {
+    pub fn new(
+        view_client: actix::Addr<near_client::ViewClientActor>,
+        cache: Arc<TokenCache>,
+        contract_cache: ContractMetadataCache,
+    ) -> Self {
+        Self {
+            view_client,
+            cache,
+            contract_cache,
+            owner_enumeration: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drops the cached metadata an update event makes stale. A
+    /// `contract_metadata_update` or a `*metadata_update` without token ids
+    /// drops everything cached for the contract.
+    pub fn invalidate(&self, event: &NearEvent) {
+        if !event.event.ends_with("metadata_update") {
+            return;
+        }
+        let contract_account_id = match &event.emit_info {
+            Some(emit_info) => emit_info.contract_account_id.clone(),
+            None => return,
+        };
+
+        let token_ids = event.token_ids();
+        if event.event == "contract_metadata_update" || token_ids.is_empty() {
+            self.contract_cache.remove(&contract_account_id);
+            self.cache
+                .remove_where(|(contract_id, _)| contract_id == &contract_account_id);
+            return;
+        }
+        token_ids.into_iter().for_each(|token_id| {
+            self.cache.remove(&(contract_account_id.clone(), token_id));
+        });
+    }
+
+    /// `nft_metadata` of NEP-171 contracts and `ft_metadata` of NEP-141
+    /// contracts, `None` for other standards or when the call fails.
+    pub async fn get_contract_metadata(
+        &self,
+        nes_config: &NesConfig,
+        event: &NearEvent,
+    ) -> anyhow::Result<Option<ContractMetadata>> {
+        let method_name = match event.standard.as_str() {
+            "nep171" => "nft_metadata",
+            "nep141" => "ft_metadata",
+            _ => return Ok(None),
+        };
+        let emit_info = match &event.emit_info {
+            Some(emit_info) => emit_info,
+            None => return Ok(None),
+        };
+        let contract_id = &emit_info.contract_account_id;
+
+        if let Some(metadata) = cached_at(&self.contract_cache, contract_id, emit_info.block_height)
+        {
+            return Ok(Some(metadata));
+        }
+
+        let response = self
+            .call_view_at_block(
+                contract_id,
+                method_name,
+                json!({}),
+                emit_info.block_height,
+                nes_config.metadata_block_fallback,
+            )
+            .await?;
+        let (response, source) = match response {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+
+        match response {
+            Ok(response) => {
+                if let QueryResponseKind::CallResult(result) = response.kind {
+                    let metadata = match method_name {
+                        "nft_metadata" => from_slice::<NftContractMetadata>(&result.result)
+                            .map(ContractMetadata::Nft),
+                        _ => from_slice::<FtContractMetadata>(&result.result)
+                            .map(ContractMetadata::Ft),
+                    };
+                    if let Ok(metadata) = metadata {
+                        if source == MetadataSource::Block {
+                            cache_at(
+                                &self.contract_cache,
+                                contract_id.clone(),
+                                emit_info.block_height,
+                                metadata.clone(),
+                            );
+                        }
+                        return Ok(Some(metadata));
+                    }
+                }
+
+                Ok(None)
+            }
+            Err(err) => {
+                tracing::error!(
+                    "{} unhandled error: {}, {:?}",
+                    method_name,
+                    contract_id,
+                    err
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn query(
+        &self,
+        request: QueryRequest,
+        block_reference: BlockReference,
+    ) -> anyhow::Result<Result<QueryResponse, QueryError>> {
+        let query = near_client::Query {
+            query_id: String::from("TODO:query_id"),
+            block_reference,
+            request,
+        };
+
+        Ok(self.view_client.send(query).await?)
+    }
+
+    /// Queries the state at `block_height`, falling back as configured
+    /// when the node no longer has that block. `None` means skipped.
+    async fn query_at_block(
+        &self,
+        request: QueryRequest,
+        block_height: u64,
+        fallback: MetadataBlockFallback,
+    ) -> anyhow::Result<Option<(Result<QueryResponse, QueryError>, MetadataSource)>> {
+        let block_reference = BlockReference::BlockId(BlockId::Height(block_height));
+        let response = self.query(request.clone(), block_reference).await?;
+
+        match response {
+            Err(QueryError::GarbageCollectedBlock { .. } | QueryError::UnknownBlock { .. }) => {
+                match fallback {
+                    MetadataBlockFallback::Latest => {
+                        let block_reference = BlockReference::Finality(Finality::Final);
+                        let response = self.query(request, block_reference).await?;
+                        Ok(Some((response, MetadataSource::Latest)))
+                    }
+                    MetadataBlockFallback::Skip => Ok(None),
+                    MetadataBlockFallback::Fail => anyhow::bail!(
+                        "State at block {} is not available for {:?}",
+                        block_height,
+                        request
+                    ),
+                }
+            }
+            response => Ok(Some((response, MetadataSource::Block))),
+        }
+    }
+
+    async fn call_view_at_block(
+        &self,
+        contract_id: &str,
+        method_name: &str,
+        args: serde_json::Value,
+        block_height: u64,
+        fallback: MetadataBlockFallback,
+    ) -> anyhow::Result<Option<(Result<QueryResponse, QueryError>, MetadataSource)>> {
+        let request = QueryRequest::CallFunction {
+            account_id: contract_id.parse()?,
+            method_name: method_name.to_string(),
+            args: FunctionArgs::from(args.to_string().into_bytes()),
+        };
+
+        self.query_at_block(request, block_height, fallback).await
+    }
+
+    pub async fn view_account(
+        &self,
+        nes_config: &NesConfig,
+        account_id: &str,
+        block_height: u64,
+    ) -> anyhow::Result<Option<AccountView>> {
+        let request = QueryRequest::ViewAccount {
+            account_id: account_id.parse()?,
+        };
+        let response = self
+            .query_at_block(request, block_height, nes_config.metadata_block_fallback)
+            .await?;
+
+        match response {
+            Some((Ok(response), _)) => match response.kind {
+                QueryResponseKind::ViewAccount(account) => Ok(Some(account)),
+                _ => Ok(None),
+            },
+            // Accounts deleted or not created yet are just left out
+            Some((Err(QueryError::UnknownAccount { .. }), _)) => Ok(None),
+            Some((Err(err), _)) => {
+                tracing::error!("view_account unhandled error: {}, {:?}", account_id, err);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_nft_token(
+        &self,
+        contract_id: &str,
+        token_id: &str,
+        block_height: u64,
+        fallback: MetadataBlockFallback,
+    ) -> anyhow::Result<TokenLookup> {
+        let cache_key = (contract_id.to_string(), token_id.to_string());
+        if let Some(token) = cached_at(&self.cache, &cache_key, block_height) {
+            return Ok(Ok((token, MetadataSource::Cached)));
+        }
+
+        let args = json!({
+            "token_id": token_id,
+        });
+        let response = self
+            .call_view_at_block(contract_id, "nft_token", args, block_height, fallback)
+            .await?;
+        let (response, source) = match response {
+            Some(response) => response,
+            None => return Ok(Err(EnrichmentStatus::NodeError)),
+        };
+
+        match response {
+            Ok(response) => match response.kind {
+                QueryResponseKind::CallResult(result) => {
+                    match from_slice::<Option<Token>>(&result.result) {
+                        Ok(Some(token)) => {
+                            self.cache_token(contract_id, &token, source, block_height);
+                            Ok(Ok((token, source)))
+                        }
+                        Ok(None) => Ok(Err(EnrichmentStatus::NotFound)),
+                        Err(err) => {
+                            tracing::warn!(
+                                "get_nft_token decode error: {}, {}, {:?}",
+                                contract_id,
+                                token_id,
+                                err
+                            );
+                            Ok(Err(EnrichmentStatus::DecodeError))
+                        }
+                    }
+                }
+                _ => Ok(Err(EnrichmentStatus::NodeError)),
+            },
+            Err(err) => {
+                tracing::error!(
+                    "get_nft_token unhandled error: {}, {}, {:?}",
+                    contract_id,
+                    token_id,
+                    err
+                );
+                Ok(Err(EnrichmentStatus::from_query_error(&err)))
             }
-        },
-    };
-
-    let response = client.send(request).await?;
-
-    match response {
-        Ok(response) => {
-            if let QueryResponseKind::CallResult(result) = response.kind {
-                let token = from_slice::<Token>(&result.result);
-                match token {
-                    Ok(token) => return Ok(Some(token)),
-                    Err(_) => return Ok(None),
+        }
+    }
+
+    /// Calls any view method and returns its JSON result, `None` when the
+    /// call fails or doesn't return JSON.
+    pub async fn view_json(
+        &self,
+        nes_config: &NesConfig,
+        contract_id: &str,
+        method_name: &str,
+        args: serde_json::Value,
+        block_height: u64,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        let response = self
+            .call_view_at_block(
+                contract_id,
+                method_name,
+                args,
+                block_height,
+                nes_config.metadata_block_fallback,
+            )
+            .await?;
+
+        match response {
+            Some((Ok(response), _)) => match response.kind {
+                QueryResponseKind::CallResult(result) => {
+                    Ok(from_slice::<serde_json::Value>(&result.result).ok())
                 }
+                _ => Ok(None),
+            },
+            Some((Err(err), _)) => {
+                tracing::error!(
+                    "{} unhandled error: {}, {:?}",
+                    method_name,
+                    contract_id,
+                    err
+                );
+                Ok(None)
             }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_nft_payout(
+        &self,
+        nes_config: &NesConfig,
+        spec: &PayoutSpec,
+        contract_id: &str,
+        token_id: &str,
+        block_height: u64,
+    ) -> anyhow::Result<Option<Payout>> {
+        let args = json!({
+            "token_id": token_id,
+            "balance": spec.balance,
+            "max_len_payout": spec.max_len_payout,
+        });
+        let response = self
+            .call_view_at_block(
+                contract_id,
+                &spec.method,
+                args,
+                block_height,
+                nes_config.metadata_block_fallback,
+            )
+            .await?;
 
-            Ok(None)
+        match response {
+            Some((Ok(response), _)) => match response.kind {
+                QueryResponseKind::CallResult(result) => {
+                    Ok(from_slice::<Payout>(&result.result).ok())
+                }
+                _ => Ok(None),
+            },
+            Some((Err(err), _)) => {
+                tracing::error!(
+                    "{} unhandled error: {}, {}, {:?}",
+                    spec.method,
+                    contract_id,
+                    token_id,
+                    err
+                );
+                Ok(None)
+            }
+            None => Ok(None),
         }
-        Err(err) => {
-            tracing::error!(
-                "get_nft_token unhandled error: {}, {}, {:?}",
+    }
+
+    /// Fetches many tokens of one contract. Cached tokens are reused, then
+    /// batch views are tried when there are at least `metadata_batch_threshold`
+    /// tokens left, and the rest is fetched with one `nft_token` call each.
+    pub async fn get_nft_tokens(
+        &self,
+        nes_config: &NesConfig,
+        contract_id: &str,
+        owner_id: Option<&str>,
+        token_ids: &[String],
+        block_height: u64,
+    ) -> anyhow::Result<HashMap<String, TokenLookup>> {
+        let fallback = nes_config.metadata_block_fallback;
+        let mut tokens = HashMap::new();
+
+        let mut missing: Vec<String> = vec![];
+        for token_id in token_ids.iter().unique() {
+            let cache_key = (contract_id.to_string(), token_id.to_string());
+            match cached_at(&self.cache, &cache_key, block_height) {
+                Some(token) => {
+                    tokens.insert(token_id.clone(), Ok((token, MetadataSource::Cached)));
+                }
+                None => missing.push(token_id.clone()),
+            }
+        }
+
+        if !missing.is_empty() && missing.len() >= nes_config.metadata_batch_threshold {
+            let batch = match (nes_config.metadata_batch_methods.get(contract_id), owner_id) {
+                (Some(method_name), _) => {
+                    self.get_batch(nes_config, contract_id, method_name, &missing, block_height)
+                        .await?
+                }
+                (None, Some(owner_id)) => {
+                    self.get_owner_tokens(nes_config, contract_id, owner_id, &missing, block_height)
+                        .await?
+                }
+                (None, None) => HashMap::new(),
+            };
+            missing.retain(|token_id| !batch.contains_key(token_id));
+            tokens.extend(
+                batch
+                    .into_iter()
+                    .map(|(token_id, token)| (token_id, Ok(token))),
+            );
+        }
+
+        let fetched = stream::iter(missing.iter())
+            .map(|token_id| async move {
+                self.get_nft_token(contract_id, token_id, block_height, fallback)
+                    .await
+                    .map(|token| (token_id.clone(), token))
+            })
+            .buffered(nes_config.metadata_concurrency.max(1))
+            .try_collect::<Vec<(String, TokenLookup)>>()
+            .await?;
+        tokens.extend(fetched);
+
+        Ok(tokens)
+    }
+
+    /// Custom batch view taking `{"token_ids": [...]}` and returning the tokens.
+    async fn get_batch(
+        &self,
+        nes_config: &NesConfig,
+        contract_id: &str,
+        method_name: &str,
+        token_ids: &[String],
+        block_height: u64,
+    ) -> anyhow::Result<HashMap<String, (Token, MetadataSource)>> {
+        let chunks =
+            token_ids
+                .chunks(nes_config.metadata_batch_size.max(1))
+                .map(|chunk| async move {
+                    let args = json!({
+                        "token_ids": chunk,
+                    });
+                    let response = self
+                        .call_view_at_block(
+                            contract_id,
+                            method_name,
+                            args,
+                            block_height,
+                            nes_config.metadata_block_fallback,
+                        )
+                        .await?;
+                    Ok::<_, anyhow::Error>(self.parse_tokens(
+                        contract_id,
+                        method_name,
+                        response,
+                        block_height,
+                    ))
+                });
+
+        let tokens = stream::iter(chunks)
+            .buffered(nes_config.metadata_concurrency.max(1))
+            .try_collect::<Vec<Vec<(Token, MetadataSource)>>>()
+            .await?;
+
+        Ok(tokens
+            .into_iter()
+            .flatten()
+            .map(|(token, source)| (token.token_id.clone(), (token, source)))
+            .collect())
+    }
+
+    /// Pages through NEP-181 `nft_tokens_for_owner` looking for `token_ids`.
+    /// Nothing is fetched when that takes more than `metadata_batch_owner_pages`
+    /// pages, or not fewer pages than there are tokens, the per-token lookups
+    /// being as cheap then. Contracts without the methods are remembered and
+    /// skipped afterwards.
+    async fn get_owner_tokens(
+        &self,
+        nes_config: &NesConfig,
+        contract_id: &str,
+        owner_id: &str,
+        token_ids: &[String],
+        block_height: u64,
+    ) -> anyhow::Result<HashMap<String, (Token, MetadataSource)>> {
+        let mut tokens = HashMap::new();
+        if self.owner_enumeration.lock().unwrap().get(contract_id) == Some(&false) {
+            return Ok(tokens);
+        }
+
+        let limit = nes_config.metadata_batch_size.max(1);
+        let supply = self
+            .get_owner_supply(nes_config, contract_id, owner_id, block_height)
+            .await?;
+        let pages = match supply {
+            Some(supply) => supply / limit + usize::from(supply % limit != 0),
+            None => return Ok(tokens),
+        };
+        if pages > nes_config.metadata_batch_owner_pages || pages >= token_ids.len() {
+            return Ok(tokens);
+        }
+
+        for page in 0..pages {
+            let args = json!({
+                "account_id": owner_id,
+                "from_index": (page * limit).to_string(),
+                "limit": limit,
+            });
+            let response = self
+                .call_view_at_block(
+                    contract_id,
+                    "nft_tokens_for_owner",
+                    args,
+                    block_height,
+                    nes_config.metadata_block_fallback,
+                )
+                .await?;
+
+            if let Some((Err(QueryError::ContractExecutionError { .. }), _)) = &response {
+                self.owner_enumeration
+                    .lock()
+                    .unwrap()
+                    .insert(contract_id.to_string(), false);
+                break;
+            }
+
+            let page_tokens =
+                self.parse_tokens(contract_id, "nft_tokens_for_owner", response, block_height);
+            let is_last_page = page_tokens.len() < limit;
+            tokens.extend(
+                page_tokens
+                    .into_iter()
+                    .filter(|(token, _)| token_ids.contains(&token.token_id))
+                    .map(|(token, source)| (token.token_id.clone(), (token, source))),
+            );
+
+            if is_last_page || tokens.len() == token_ids.len() {
+                break;
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// NEP-181 `nft_supply_for_owner`, `None` when it can't be read.
+    async fn get_owner_supply(
+        &self,
+        nes_config: &NesConfig,
+        contract_id: &str,
+        owner_id: &str,
+        block_height: u64,
+    ) -> anyhow::Result<Option<usize>> {
+        let response = self
+            .call_view_at_block(
                 contract_id,
-                token_id,
-                err
+                "nft_supply_for_owner",
+                json!({ "account_id": owner_id }),
+                block_height,
+                nes_config.metadata_block_fallback,
+            )
+            .await?;
+
+        Ok(match response {
+            Some((Ok(response), _)) => match response.kind {
+                QueryResponseKind::CallResult(result) => {
+                    // U128 is serialized as a string
+                    from_slice::<String>(&result.result)
+                        .ok()
+                        .and_then(|supply| supply.parse().ok())
+                }
+                _ => None,
+            },
+            Some((Err(QueryError::ContractExecutionError { .. }), _)) => {
+                self.owner_enumeration
+                    .lock()
+                    .unwrap()
+                    .insert(contract_id.to_string(), false);
+                None
+            }
+            _ => None,
+        })
+    }
+
+    fn parse_tokens(
+        &self,
+        contract_id: &str,
+        method_name: &str,
+        response: Option<(Result<QueryResponse, QueryError>, MetadataSource)>,
+        block_height: u64,
+    ) -> Vec<(Token, MetadataSource)> {
+        let (response, source) = match response {
+            Some(response) => response,
+            None => return vec![],
+        };
+
+        match response {
+            Ok(response) => match response.kind {
+                QueryResponseKind::CallResult(result) => {
+                    from_slice::<Vec<Option<Token>>>(&result.result)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .flatten()
+                        .map(|token| {
+                            self.cache_token(contract_id, &token, source, block_height);
+                            (token, source)
+                        })
+                        .collect()
+                }
+                _ => vec![],
+            },
+            Err(err) => {
+                tracing::error!(
+                    "{} unhandled error: {}, {:?}",
+                    method_name,
+                    contract_id,
+                    err
+                );
+                vec![]
+            }
+        }
+    }
+
+    fn cache_token(
+        &self,
+        contract_id: &str,
+        token: &Token,
+        source: MetadataSource,
+        block_height: u64,
+    ) {
+        // Only the state at the event block is valid for later events
+        if source == MetadataSource::Block {
+            cache_at(
+                &self.cache,
+                (contract_id.to_string(), token.token_id.clone()),
+                block_height,
+                token.clone(),
             );
-            Ok(None)
         }
     }
 }
 
-pub async fn get_metadatas(
-    client: &actix::Addr<near_client::ViewClientActor>,
+#[derive(Debug, Default)]
+pub struct Metadatas {
+    pub _ids: Vec<Option<String>>,
+    pub metadatas: Vec<Option<TokenMetadata>>,
+    pub extras: Vec<Option<serde_json::Value>>,
+    pub sources: Vec<Option<MetadataSource>>,
+    pub statuses: Vec<EnrichmentStatus>,
+}
+
+/// Lines up the fetched `tokens` with `token_ids`.
+pub fn get_metadatas(
     contract_account_id: &str,
     token_ids: &[String],
-) -> anyhow::Result<(
-    Vec<Option<String>>,
-    Vec<Option<TokenMetadata>>,
-    Vec<Option<serde_json::Value>>,
-)> {
+    tokens: &HashMap<String, TokenLookup>,
+) -> Metadatas {
     let _ids: Vec<Option<String>> = token_ids
         .iter()
         .map(|token_id| Some(format!("{}:{}", contract_account_id, &token_id)))
         .collect();
 
-    let metadatas: Vec<Option<TokenMetadata>> = token_ids
+    let statuses: Vec<EnrichmentStatus> = token_ids
         .iter()
-        .map(|token_id| get_nft_token(client, contract_account_id, token_id))
-        .collect::<FuturesOrdered<_>>()
-        .try_collect::<Vec<Option<Token>>>()
-        .await?
+        .map(|token_id| match tokens.get(token_id) {
+            Some(Ok(_)) => EnrichmentStatus::Ok,
+            Some(Err(status)) => *status,
+            None => EnrichmentStatus::NodeError,
+        })
+        .collect();
+
+    let tokens: Vec<Option<&(Token, MetadataSource)>> = token_ids
+        .iter()
+        .map(|token_id| tokens.get(token_id).and_then(|token| token.as_ref().ok()))
+        .collect();
+
+    let metadatas: Vec<Option<TokenMetadata>> = tokens
         .iter()
         .map(|token| match token {
-            Some(token) => token.metadata.clone(),
+            Some((token, _)) => token.metadata.clone(),
             None => None,
         })
         .collect();
 
+    let sources: Vec<Option<MetadataSource>> = tokens
+        .iter()
+        .map(|token| token.map(|(_, source)| *source))
+        .collect();
+
     let extras: Vec<Option<serde_json::Value>> = metadatas
         .iter()
         .map(|metadata| match metadata {
@@ -151,5 +906,59 @@ pub async fn get_metadatas(
         })
         .collect();
 
-    Ok((_ids, metadatas, extras))
+    Metadatas {
+        _ids,
+        metadatas,
+        extras,
+        sources,
+        statuses,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn caches_by_block() {
+        let token: Token = serde_json::from_value(json!({
+            "token_id": "7",
+            "owner_id": "a.near",
+        }))
+        .unwrap();
+
+        actix::System::new().block_on(async {
+            // The view client is gone, only cached tokens can be returned
+            let (sender, receiver) = actix::dev::channel::channel(1);
+            drop(receiver);
+            let client = TokenClient::new(
+                actix::Addr::new(sender),
+                Arc::new(TokenCache::new(10, Duration::from_secs(60))),
+                ContractMetadataCache::new(1, Duration::from_secs(60)),
+            );
+            let fallback = MetadataBlockFallback::Skip;
+
+            client.cache_token("nft.near", &token, MetadataSource::Block, 5);
+            let (cached, source) = client
+                .get_nft_token("nft.near", "7", 6, fallback)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((cached, source), (token.clone(), MetadataSource::Cached));
+            // The state at block 5 is newer than the event
+            assert!(client
+                .get_nft_token("nft.near", "7", 4, fallback)
+                .await
+                .is_err());
+
+            // An earlier block doesn't replace a later one
+            client.cache_token("nft.near", &token, MetadataSource::Block, 3);
+            assert!(client
+                .get_nft_token("nft.near", "7", 4, fallback)
+                .await
+                .is_err());
+        });
+    }
 }
@@ -0,0 +1,434 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    admin::{
+        AdminClient, AdminOptions, AlterConfig, ConfigSource, NewPartitions, NewTopic,
+        ResourceSpecifier, TopicReplication,
+    },
+    client::DefaultClientContext,
+    consumer::{Consumer, StreamConsumer},
+    types::RDKafkaErrorCode,
+};
+use serde::Deserialize;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::{configs::NesConfig, matcher::Pattern};
+
+/// Creation settings for topics whose name matches `pattern`, from the
+/// `[[topic_specs]]` table of `nes.toml`. The first matching spec is used,
+/// unset fields fall back to `new_topic_partitions`/`new_topic_replication`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TopicSpec {
+    pub pattern: Pattern,
+    pub partitions: Option<i32>,
+    pub replication: Option<i32>,
+    /// Topic configs such as `retention.ms` or `cleanup.policy`
+    #[serde(default)]
+    pub config: HashMap<String, String>,
+}
+
+impl TopicSpec {
+    /// Partition count to grow a topic of `partitions` to, `None` when it
+    /// has enough. Partitions are never removed.
+    fn added_partitions(&self, partitions: usize) -> Option<usize> {
+        self.partitions
+            .map(|spec_partitions| spec_partitions as usize)
+            .filter(|spec_partitions| *spec_partitions > partitions)
+    }
+
+    /// Config to set on a topic whose config `values` differ from the spec,
+    /// `None` when it is up to date. AlterConfigs replaces the whole topic
+    /// config, so the `overrides` already set on the topic are kept.
+    fn altered_config(
+        &self,
+        values: &HashMap<String, String>,
+        overrides: &HashMap<String, String>,
+    ) -> Option<BTreeMap<String, String>> {
+        let outdated = self
+            .config
+            .iter()
+            .any(|(key, value)| values.get(key) != Some(value));
+        outdated.then(|| {
+            overrides
+                .iter()
+                .chain(self.config.iter())
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct KnownTopics {
+    /// Topic name to its partition count
+    names: HashMap<String, usize>,
+    reconciled: HashSet<String>,
+    /// When the reconciliation of a topic last failed
+    reconcile_failed: HashMap<String, Instant>,
+    refreshed_at: Option<Instant>,
+}
+
+impl KnownTopics {
+    /// A topic whose reconciliation failed is tried again once per refresh
+    /// interval, not for every record.
+    fn is_ready(&self, nes_config: &NesConfig, topic: &str) -> bool {
+        let failed_recently = self
+            .reconcile_failed
+            .get(topic)
+            .map(|failed_at| failed_at.elapsed() < refresh_interval(nes_config))
+            .unwrap_or(false);
+        self.names.contains_key(topic)
+            && (!nes_config.reconcile_topics || self.reconciled.contains(topic) || failed_recently)
+    }
+
+    fn is_fresh(&self, nes_config: &NesConfig) -> bool {
+        self.refreshed_at
+            .map(|refreshed_at| refreshed_at.elapsed() < refresh_interval(nes_config))
+            .unwrap_or(false)
+    }
+}
+
+fn refresh_interval(nes_config: &NesConfig) -> Duration {
+    Duration::from_secs(nes_config.topic_cache_refresh_secs)
+}
+
+/// Keeps the set of topics known to exist on the brokers, so creating
+/// missing topics doesn't cost a metadata round-trip for every record.
+pub struct TopicManager {
+    consumer: Arc<StreamConsumer>,
+    admin_client: AdminClient<DefaultClientContext>,
+    known_topics: RwLock<KnownTopics>,
+    /// Topics being created or reconciled, other senders to them wait
+    in_flight: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl TopicManager {
+    pub fn new(nes_config: &NesConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            consumer: Arc::new(nes_config.kafka_config.create()?),
+            admin_client: nes_config.kafka_config.create()?,
+            known_topics: RwLock::new(KnownTopics::default()),
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Creates `topic` when missing, or reconciles it. The known topics are
+    /// only locked to be read or updated, not during the Kafka calls, so
+    /// records to ready topics are not held up by the admin requests.
+    pub async fn ensure_topic(&self, nes_config: &NesConfig, topic: &str) -> anyhow::Result<()> {
+        if !nes_config.force_create_new_topic || self.is_ready(nes_config, topic).await {
+            return Ok(());
+        }
+
+        let topic_lock = Arc::clone(
+            self.in_flight
+                .lock()
+                .unwrap()
+                .entry(topic.to_string())
+                .or_default(),
+        );
+        let _in_flight = topic_lock.lock().await;
+        if self.is_ready(nes_config, topic).await {
+            return Ok(());
+        }
+
+        let result = self.prepare_topic(nes_config, topic).await;
+        self.in_flight.lock().unwrap().remove(topic);
+        result
+    }
+
+    async fn is_ready(&self, nes_config: &NesConfig, topic: &str) -> bool {
+        let known_topics = self.known_topics.read().await;
+        known_topics.is_ready(nes_config, topic) && known_topics.is_fresh(nes_config)
+    }
+
+    async fn prepare_topic(&self, nes_config: &NesConfig, topic: &str) -> anyhow::Result<()> {
+        let known_topics = self.known_topics.read().await;
+        let needs_refresh =
+            !known_topics.is_fresh(nes_config) || !known_topics.names.contains_key(topic);
+        drop(known_topics);
+        if needs_refresh {
+            self.refresh().await;
+        }
+
+        let partitions = self.known_topics.read().await.names.get(topic).copied();
+        match partitions {
+            Some(partitions) => {
+                let reconciled = self.known_topics.read().await.reconciled.contains(topic);
+                if nes_config.reconcile_topics && !reconciled {
+                    if let Err(err) = self.reconcile_topic(nes_config, topic, partitions).await {
+                        warn!("Could not reconcile Kafka topic {}: {:?}", topic, err);
+                        self.known_topics
+                            .write()
+                            .await
+                            .reconcile_failed
+                            .insert(topic.to_string(), Instant::now());
+                        return Ok(());
+                    }
+                }
+            }
+            None => {
+                let partitions = self.create_topic(nes_config, topic).await?;
+                self.known_topics
+                    .write()
+                    .await
+                    .names
+                    .insert(topic.to_string(), partitions);
+            }
+        }
+        let mut known_topics = self.known_topics.write().await;
+        known_topics.reconcile_failed.remove(topic);
+        known_topics.reconciled.insert(topic.to_string());
+
+        Ok(())
+    }
+
+    /// Drops a topic from the cache, e.g. after the broker reported it unknown.
+    pub async fn forget(&self, topic: &str) {
+        let mut known_topics = self.known_topics.write().await;
+        known_topics.names.remove(topic);
+        known_topics.reconciled.remove(topic);
+        known_topics.reconcile_failed.remove(topic);
+    }
+
+    /// Has the known topics reconciled again, after `topic_specs` changed.
+    pub async fn forget_reconciled(&self) {
+        let mut known_topics = self.known_topics.write().await;
+        known_topics.reconciled.clear();
+        known_topics.reconcile_failed.clear();
+    }
+
+    /// Fetches the topics from the brokers. The blocking metadata request
+    /// runs off the actix thread.
+    async fn refresh(&self) {
+        let consumer = Arc::clone(&self.consumer);
+        let names = tokio::task::spawn_blocking(move || {
+            consumer
+                .fetch_metadata(None, Duration::from_secs(1))
+                .map(|metadata| {
+                    metadata
+                        .topics()
+                        .iter()
+                        .map(|t| (t.name().to_string(), t.partitions().len()))
+                        .collect::<HashMap<String, usize>>()
+                })
+        })
+        .await;
+
+        let names = match names {
+            Ok(Ok(names)) => names,
+            Ok(Err(err)) => {
+                warn!("Could not fetch Kafka metadata: {:?}", err);
+                return;
+            }
+            Err(err) => {
+                warn!("Could not fetch Kafka metadata: {:?}", err);
+                return;
+            }
+        };
+        debug!("Kafka topics: {:?}", names.keys());
+
+        let mut known_topics = self.known_topics.write().await;
+        known_topics.names = names;
+        known_topics.refreshed_at = Some(Instant::now());
+    }
+
+    async fn create_topic(&self, nes_config: &NesConfig, topic: &str) -> anyhow::Result<usize> {
+        let spec = nes_config.topic_spec(topic);
+        let partitions = spec
+            .and_then(|spec| spec.partitions)
+            .unwrap_or(nes_config.new_topic_partitions);
+        let replication = spec
+            .and_then(|spec| spec.replication)
+            .unwrap_or(nes_config.new_topic_replication);
+
+        let mut new_topic = NewTopic::new(topic, partitions, TopicReplication::Fixed(replication));
+        if let Some(spec) = spec {
+            for (key, value) in spec.config.iter() {
+                new_topic = new_topic.set(key, value);
+            }
+        }
+
+        let results = self
+            .admin_client
+            .create_topics(&[new_topic], &AdminOptions::new())
+            .await?;
+
+        for result in results {
+            match result {
+                Ok(status) => info!("Kafka created new topic: {:?}", status),
+                Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((_, err)) => return Err(err.into()),
+            }
+        }
+
+        Ok(partitions as usize)
+    }
+
+    /// Brings an existing topic in line with its spec: adds partitions up
+    /// to `partitions` and applies `config`. Partitions are never removed
+    /// and the replication factor of an existing topic is left unchanged.
+    async fn reconcile_topic(
+        &self,
+        nes_config: &NesConfig,
+        topic: &str,
+        partitions: usize,
+    ) -> anyhow::Result<()> {
+        let spec = match nes_config.topic_spec(topic) {
+            Some(spec) => spec,
+            None => return Ok(()),
+        };
+
+        if let Some(spec_partitions) = spec.added_partitions(partitions) {
+            let results = self
+                .admin_client
+                .create_partitions(
+                    &[NewPartitions::new(topic, spec_partitions)],
+                    &AdminOptions::new(),
+                )
+                .await?;
+            for result in results {
+                result.map_err(|e| e.1)?;
+            }
+            info!(
+                "Kafka topic {} partitions increased from {} to {}",
+                topic, partitions, spec_partitions
+            );
+        }
+
+        if spec.config.is_empty() {
+            return Ok(());
+        }
+
+        let resources = self
+            .admin_client
+            .describe_configs(&[ResourceSpecifier::Topic(topic)], &AdminOptions::new())
+            .await?;
+
+        for resource in resources {
+            let resource = resource?;
+            let values = |dynamic_only: bool| {
+                resource
+                    .entries
+                    .iter()
+                    .filter(|entry| !dynamic_only || entry.source == ConfigSource::DynamicTopic)
+                    .filter_map(|entry| Some((entry.name.clone(), entry.value.clone()?)))
+                    .collect::<HashMap<String, String>>()
+            };
+            let config = match spec.altered_config(&values(false), &values(true)) {
+                Some(config) => config,
+                None => continue,
+            };
+
+            let mut alter_config = AlterConfig::new(ResourceSpecifier::Topic(topic));
+            for (key, value) in config.iter() {
+                alter_config = alter_config.set(key, value);
+            }
+
+            let results = self
+                .admin_client
+                .alter_configs(&[alter_config], &AdminOptions::new())
+                .await?;
+            for result in results {
+                result.map_err(|e| e.1)?;
+            }
+            info!("Kafka topic {} config updated: {:?}", topic, spec.config);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nes_config() -> NesConfig {
+        serde_json::from_value(serde_json::json!({
+            "force_create_new_topic": true,
+            "reconcile_topics": true,
+            "topic_specs": [
+                {"pattern": "nft_*", "partitions": 6, "config": {"retention.ms": "1000"}},
+                {"pattern": "*", "replication": 3},
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn specs() {
+        let nes_config = nes_config();
+        let spec = nes_config.topic_spec("nft_transfer").unwrap();
+        assert_eq!(spec.partitions, Some(6));
+        assert_eq!(
+            nes_config.topic_spec("ft_transfer").unwrap().replication,
+            Some(3)
+        );
+
+        assert_eq!(spec.added_partitions(3), Some(6));
+        assert_eq!(spec.added_partitions(6), None);
+        assert_eq!(spec.added_partitions(8), None);
+
+        let map = |entries: &[(&str, &str)]| {
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>()
+        };
+        let overrides = map(&[("cleanup.policy", "compact")]);
+        let values = map(&[("cleanup.policy", "compact"), ("retention.ms", "1000")]);
+        assert_eq!(spec.altered_config(&values, &overrides), None);
+        let values = map(&[("cleanup.policy", "compact"), ("retention.ms", "5000")]);
+        let config = spec.altered_config(&values, &overrides).unwrap();
+        assert_eq!(config["cleanup.policy"], "compact");
+        assert_eq!(config["retention.ms"], "1000");
+        assert_eq!(config.len(), 2);
+    }
+
+    #[test]
+    fn known_topics() {
+        let nes_config = nes_config();
+        let mut known_topics = KnownTopics::default();
+        assert!(!known_topics.is_fresh(&nes_config));
+        assert!(!known_topics.is_ready(&nes_config, "nft_mint"));
+
+        known_topics.refreshed_at = Some(Instant::now());
+        known_topics.names.insert("nft_mint".to_string(), 1);
+        assert!(known_topics.is_fresh(&nes_config));
+        // Still to be reconciled
+        assert!(!known_topics.is_ready(&nes_config, "nft_mint"));
+
+        known_topics
+            .reconcile_failed
+            .insert("nft_mint".to_string(), Instant::now());
+        assert!(known_topics.is_ready(&nes_config, "nft_mint"));
+        known_topics.reconcile_failed.insert(
+            "nft_mint".to_string(),
+            Instant::now() - refresh_interval(&nes_config),
+        );
+        assert!(!known_topics.is_ready(&nes_config, "nft_mint"));
+
+        known_topics.reconciled.insert("nft_mint".to_string());
+        assert!(known_topics.is_ready(&nes_config, "nft_mint"));
+
+        actix::System::new().block_on(async {
+            // Ready topics are not fetched, created or reconciled again
+            let mut consumer_config = nes_config.clone();
+            consumer_config.kafka_config.set("group.id", "nes-test");
+            let topics = TopicManager::new(&consumer_config).unwrap();
+            *topics.known_topics.write().await = known_topics;
+            topics.ensure_topic(&nes_config, "nft_mint").await.unwrap();
+            assert!(topics.in_flight.lock().unwrap().is_empty());
+
+            topics.forget_reconciled().await;
+            assert!(!topics.is_ready(&nes_config, "nft_mint").await);
+            topics.forget("nft_mint").await;
+            assert!(topics.known_topics.read().await.names.is_empty());
+        });
+    }
+}